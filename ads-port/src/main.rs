@@ -3,6 +3,7 @@ use eva_ads_common::{AdsError, AdsResult, AmsAddr, AmsCmd, AmsPacket, AmsPacketH
 use eva_common::err_logger;
 use eva_sdk::prelude::*;
 use eva_sdk::service::{poc, set_poc};
+use eva_sim_pcap::{Capture, Direction};
 use once_cell::sync::{Lazy, OnceCell};
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
@@ -43,6 +44,8 @@ type RouteMap = BTreeMap<AmsAddr, RouteEntry>;
 
 static ADS_ROUTES: Lazy<Mutex<RouteMap>> = Lazy::new(<_>::default);
 
+static PCAP: Lazy<Mutex<Option<Capture<SocketAddr>>>> = Lazy::new(<_>::default);
+
 #[cfg(not(feature = "std-alloc"))]
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
@@ -56,6 +59,37 @@ fn is_verbose() -> bool {
     VERBOSE.load(atomic::Ordering::Relaxed)
 }
 
+fn pcap_start(path: &str) -> EResult<()> {
+    let capture = Capture::create(path).map_err(Error::io)?;
+    if let Some(prev) = PCAP.lock().replace(capture) {
+        info!("pcap capture stopped: {}", prev.path());
+    }
+    info!("pcap capture started: {}", path);
+    Ok(())
+}
+
+fn pcap_stop() {
+    if let Some(capture) = PCAP.lock().take() {
+        info!("pcap capture stopped: {}", capture.path());
+    }
+}
+
+/// Writes an AMS/TCP packet if the capture is active
+fn pcap_packet(client: SocketAddr, server: SocketAddr, direction: Direction, data: &[u8]) {
+    let mut pcap = PCAP.lock();
+    let Some(capture) = pcap.as_mut() else {
+        return;
+    };
+    if let Err(e) = capture.write_tcp(client, client, server, direction, data) {
+        error!(
+            "pcap capture {} write error: {}, stopped",
+            capture.path(),
+            e
+        );
+        pcap.take();
+    }
+}
+
 struct Handlers {
     info: ServiceInfo,
 }
@@ -111,6 +145,44 @@ impl RpcHandlers for Handlers {
                     Err(RpcError::params(None))
                 }
             }
+            "pcap.start" => {
+                #[derive(Deserialize)]
+                #[serde(deny_unknown_fields)]
+                struct PcapPayload {
+                    path: String,
+                }
+                if payload.is_empty() {
+                    Err(RpcError::params(None))
+                } else {
+                    let p: PcapPayload = unpack(payload)?;
+                    pcap_start(&p.path)?;
+                    Ok(None)
+                }
+            }
+            "pcap.stop" => {
+                if payload.is_empty() {
+                    pcap_stop();
+                    Ok(None)
+                } else {
+                    Err(RpcError::params(None))
+                }
+            }
+            "pcap.status" => {
+                #[derive(Serialize)]
+                struct PcapStatus {
+                    active: bool,
+                    path: Option<String>,
+                }
+                if payload.is_empty() {
+                    let path = PCAP.lock().as_ref().map(|c| c.path().to_owned());
+                    Ok(Some(pack(&PcapStatus {
+                        active: path.is_some(),
+                        path,
+                    })?))
+                } else {
+                    Err(RpcError::params(None))
+                }
+            }
             _ => svc_handle_default_rpc(method, &self.info),
         }
     }
@@ -150,6 +222,7 @@ async fn handle_connection(
     client_id: ClientId,
 ) -> EResult<()> {
     let verbose = is_verbose();
+    let local_addr = stream.local_addr()?;
     loop {
         let mut data = [0_u8; 6];
         if let Err(e) = stream.read_exact(&mut data).await {
@@ -164,6 +237,11 @@ async fn handle_connection(
         }
         let mut buf = vec![0_u8; usize::try_from(header.length)?];
         tokio::time::timeout(timeout, stream.read_exact(&mut buf)).await??;
+        if PCAP.lock().is_some() {
+            let mut frame = data.to_vec();
+            frame.extend(&buf);
+            pcap_packet(addr, local_addr, Direction::Inbound, &frame);
+        }
         let mut packet = AmsPacket::read(&mut Cursor::new(buf)).map_err(Error::io)?;
         if verbose {
             info!("{} IN {}, {}", client_id, header, packet);
@@ -193,7 +271,9 @@ async fn handle_connection(
         };
         reply_header.write(&mut buf).map_err(Error::io)?;
         packet.write(&mut buf).map_err(Error::io)?;
-        tokio::time::timeout(timeout, stream.write_all(&buf.into_inner())).await??;
+        let reply = buf.into_inner();
+        pcap_packet(addr, local_addr, Direction::Outbound, &reply);
+        tokio::time::timeout(timeout, stream.write_all(&reply)).await??;
     }
     Ok(())
 }
@@ -214,6 +294,9 @@ async fn run_server(addr: &str, me: &str, timeout: Duration) -> EResult<()> {
                 error!("handler error {}: {}", addr, e);
             }
             info!("client disconnected: {} {}", addr, client_id);
+            if let Some(capture) = PCAP.lock().as_mut() {
+                capture.remove_flow(&addr);
+            }
             RPC.get()
                 .unwrap()
                 .client()
@@ -235,6 +318,8 @@ async fn run_server(addr: &str, me: &str, timeout: Duration) -> EResult<()> {
 struct Config {
     listen: String,
     #[serde(default)]
+    pcap: Option<String>,
+    #[serde(default)]
     verbose: bool,
 }
 
@@ -263,6 +348,9 @@ async fn main(mut initial: Initial) -> EResult<()> {
     set_poc(Some(Duration::from_secs(1)));
     let mut info = ServiceInfo::new(AUTHOR, VERSION, DESCRIPTION);
     info.add_method(ServiceMethod::new("list"));
+    info.add_method(ServiceMethod::new("pcap.start").required("path"));
+    info.add_method(ServiceMethod::new("pcap.stop"));
+    info.add_method(ServiceMethod::new("pcap.status"));
     let rpc = initial.init_rpc(Handlers { info }).await?;
     initial.drop_privileges()?;
     let client = rpc.client().clone();
@@ -270,6 +358,9 @@ async fn main(mut initial: Initial) -> EResult<()> {
         .map_err(|_| Error::core("Unable to set RPC"))?;
     svc_init_logs(&initial, client.clone())?;
     tokio::spawn(route_cleaner());
+    if let Some(ref path) = config.pcap {
+        pcap_start(path)?;
+    }
     let me = initial.id().to_owned();
     tokio::spawn(async move {
         loop {
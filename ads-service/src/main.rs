@@ -55,17 +55,6 @@ struct DeviceState {
 
 err_logger!();
 
-trait SwapModbusEndianess {
-    fn to_swapped_modbus_endianness(&self) -> Self;
-}
-
-impl SwapModbusEndianess for f32 {
-    fn to_swapped_modbus_endianness(&self) -> Self {
-        let b = self.to_be_bytes();
-        Self::from_be_bytes([b[2], b[3], b[0], b[1]])
-    }
-}
-
 struct Handlers {
     info: ServiceInfo,
     me: AmsAddr,
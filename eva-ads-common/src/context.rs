@@ -176,7 +176,7 @@ impl Context {
             Err(Error::busy("the varialbe already exists"))
         }
     }
-    pub fn get_variable_entry_by_path(&self, path: &str) -> Result<VariableEntry, AdsError> {
+    pub fn get_variable_entry_by_path(&self, path: &str) -> Result<VariableEntry<'_>, AdsError> {
         Ok(get_var!(self.variables, path))
     }
     #[inline]
@@ -242,7 +242,7 @@ pub struct VariableData {
 }
 
 impl VariableData {
-    pub fn as_entry(&self, array_index: Option<ArrayIndex>) -> Result<VariableEntry, AdsError> {
+    pub fn as_entry(&self, array_index: Option<ArrayIndex>) -> Result<VariableEntry<'_>, AdsError> {
         let dt_size = self.data_type.size();
         let (pos, size, array_len) = if let Some(idx) = array_index {
             if let Some(length) = idx.length() {
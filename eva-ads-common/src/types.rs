@@ -926,7 +926,7 @@ pub const DATA_TYPES: &[DataType] = &[
 
 #[allow(dead_code)]
 pub static DATA_TYPES_NAMES_LEN: Lazy<usize> =
-    Lazy::new(|| DATA_TYPES.iter().map(|v| v.as_str().as_bytes().len()).sum());
+    Lazy::new(|| DATA_TYPES.iter().map(|v| v.as_str().len()).sum());
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, BinRead, BinWrite, Serialize, Deserialize)]
 #[brw(repr = u32)]
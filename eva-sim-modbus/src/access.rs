@@ -0,0 +1,190 @@
+//! Register-level access rules
+use crate::data::Table;
+use crate::functions::{
+    EXCEPTION_ILLEGAL_DATA_ADDRESS, EXCEPTION_ILLEGAL_FUNCTION, FC_MASK_WRITE_REGISTER,
+    FC_READ_EXCEPTION_STATUS, FC_READ_FIFO_QUEUE, FIFO_MAX_COUNT,
+};
+use serde::Deserialize;
+use std::net::IpAddr;
+
+#[derive(Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    ReadOnly,
+    WriteOnly,
+    Forbidden,
+}
+
+/// Exception, returned to clients for denied requests
+#[derive(Deserialize, Default, Copy, Clone, Eq, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Exception {
+    IllegalFunction,
+    #[default]
+    IllegalDataAddress,
+}
+
+impl Exception {
+    fn code(self) -> u8 {
+        match self {
+            Exception::IllegalFunction => EXCEPTION_ILLEGAL_FUNCTION,
+            Exception::IllegalDataAddress => EXCEPTION_ILLEGAL_DATA_ADDRESS,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub(crate) enum Operation {
+    Read,
+    Write,
+}
+
+/// Restricts access to a register range
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AccessRule {
+    table: Table,
+    address: u16,
+    #[serde(default = "default_count")]
+    count: u16,
+    access: Access,
+    #[serde(default)]
+    exception: Exception,
+    /// clients with the listed IP addresses are not restricted
+    #[serde(default)]
+    allow: Vec<IpAddr>,
+}
+
+#[inline]
+fn default_count() -> u16 {
+    1
+}
+
+impl AccessRule {
+    pub fn new(table: Table, address: u16, count: u16, access: Access) -> Self {
+        Self {
+            table,
+            address,
+            count,
+            access,
+            exception: <_>::default(),
+            allow: Vec::new(),
+        }
+    }
+    pub fn exception(mut self, exception: Exception) -> Self {
+        self.exception = exception;
+        self
+    }
+    pub fn allow(mut self, allow: Vec<IpAddr>) -> Self {
+        self.allow = allow;
+        self
+    }
+    /// Returns true if the rule denies the operation for the requested range, unless the
+    /// client is allow-listed
+    pub(crate) fn denies(&self, request: &Request) -> bool {
+        let denied = match self.access {
+            Access::ReadOnly => request.op == Operation::Write,
+            Access::WriteOnly => request.op == Operation::Read,
+            Access::Forbidden => true,
+        };
+        denied
+            && self.table == request.table
+            && u32::from(self.address) < u32::from(request.reg) + u32::from(request.count)
+            && u32::from(request.reg) < u32::from(self.address) + u32::from(self.count)
+    }
+    /// Returns the exception code if the client is not allow-listed
+    pub(crate) fn check(&self, ip: Option<IpAddr>) -> Option<u8> {
+        if ip.is_some_and(|ip| self.allow.contains(&ip)) {
+            None
+        } else {
+            Some(self.exception.code())
+        }
+    }
+}
+
+/// Context range, requested by a client
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct Request {
+    table: Table,
+    reg: u16,
+    count: u16,
+    op: Operation,
+}
+
+impl Request {
+    /// The first register and the number of registers
+    pub(crate) fn range(&self) -> (u16, u16) {
+        (self.reg, self.count)
+    }
+    pub(crate) fn is_write(&self) -> bool {
+        self.op == Operation::Write
+    }
+    /// Requests of functions, processed by rmodbus, returns None for functions, which do not
+    /// access the context
+    pub(crate) fn new(func: u8, reg: u16, count: u16) -> Option<Self> {
+        let (table, op) = match func {
+            1 => (Table::Coil, Operation::Read),
+            2 => (Table::Discrete, Operation::Read),
+            3 => (Table::Holding, Operation::Read),
+            4 => (Table::Input, Operation::Read),
+            5 | 15 => (Table::Coil, Operation::Write),
+            6 | 16 => (Table::Holding, Operation::Write),
+            _ => return None,
+        };
+        Some(Self {
+            table,
+            reg,
+            count,
+            op,
+        })
+    }
+    /// Requests of extended functions, parsed from the PDU
+    pub(crate) fn extended(pdu: &[u8]) -> Option<Self> {
+        let reg = || pdu.get(1..3).map(|v| u16::from_be_bytes([v[0], v[1]]));
+        let (table, op, reg, count) = match *pdu.first()? {
+            // the exception status is mapped to coils 0-7
+            FC_READ_EXCEPTION_STATUS => (Table::Coil, Operation::Read, 0, 8),
+            FC_MASK_WRITE_REGISTER => (Table::Holding, Operation::Write, reg()?, 1),
+            // the pointer register and up to 31 queued values
+            FC_READ_FIFO_QUEUE => (Table::Holding, Operation::Read, reg()?, FIFO_MAX_COUNT + 1),
+            _ => return None,
+        };
+        Some(Self {
+            table,
+            reg,
+            count,
+            op,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Access, AccessRule, Exception, Request};
+    use crate::data::Table;
+
+    #[test]
+    fn access_rules() {
+        let rule = AccessRule::new(Table::Holding, 10, 4, Access::ReadOnly);
+        assert!(rule.denies(&Request::new(16, 8, 3).unwrap()));
+        assert!(!rule.denies(&Request::new(16, 14, 3).unwrap()));
+        assert!(!rule.denies(&Request::new(3, 10, 4).unwrap()));
+        assert!(!rule.denies(&Request::new(15, 10, 4).unwrap()));
+        assert_eq!(rule.check(None), Some(0x02));
+        let rule = AccessRule::new(Table::Coil, 0, 1, Access::Forbidden)
+            .exception(Exception::IllegalFunction)
+            .allow(vec!["10.0.0.1".parse().unwrap()]);
+        assert!(rule.denies(&Request::extended(&[0x07]).unwrap()));
+        assert_eq!(rule.check(Some("10.0.0.2".parse().unwrap())), Some(0x01));
+        assert_eq!(rule.check(Some("10.0.0.1".parse().unwrap())), None);
+        assert!(Request::new(0x16, 0, 1).is_none());
+        assert!(Request::extended(&[0x14, 7, 6, 0, 1]).is_none());
+        assert!(Request::extended(&[0x16]).is_none());
+        // FIFO values, following the pointer, are covered
+        let rule = AccessRule::new(Table::Holding, 101, 31, Access::Forbidden);
+        let request = Request::extended(&[0x18, 0, 100]).unwrap();
+        assert!(rule.denies(&request));
+        assert_eq!(rule.check(None), Some(0x02));
+        assert!(!rule.denies(&Request::extended(&[0x18, 0, 132]).unwrap()));
+    }
+}
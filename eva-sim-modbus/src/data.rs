@@ -0,0 +1,266 @@
+use eva_common::value::Value;
+use eva_common::{EResult, Error};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Default, Copy, Clone, Eq, PartialEq, Debug)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum DataType {
+    #[default]
+    Int,
+    Uint,
+    Dint,
+    Udint,
+    Lint,
+    Ulint,
+    /// IEEE 754 f32 with the low word first (REALB with swapped words)
+    Real,
+    /// IEEE 754 f32 with the high word first
+    Realb,
+    /// two chars per register, zero-padded
+    String,
+}
+
+/// Modbus context table
+#[derive(Deserialize, Serialize, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Table {
+    #[serde(alias = "c")]
+    Coil,
+    #[serde(alias = "d")]
+    Discrete,
+    #[serde(alias = "i")]
+    Input,
+    #[serde(alias = "h")]
+    Holding,
+}
+
+impl Table {
+    pub fn is_bit(self) -> bool {
+        matches!(self, Table::Coil | Table::Discrete)
+    }
+}
+
+#[derive(Deserialize, Serialize, Default, Copy, Clone, Eq, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    #[default]
+    Big,
+    Little,
+}
+
+impl DataType {
+    /// Registers occupied, None for STRING which has a custom length
+    pub fn size(self) -> Option<u16> {
+        match self {
+            DataType::Int | DataType::Uint => Some(1),
+            DataType::Dint | DataType::Udint | DataType::Real | DataType::Realb => Some(2),
+            DataType::Lint | DataType::Ulint => Some(4),
+            DataType::String => None,
+        }
+    }
+}
+
+/// Converts typed values to/from Modbus registers
+#[derive(Clone, Debug)]
+pub struct Encoding {
+    tp: DataType,
+    registers: u16,
+    byte_order: Order,
+    word_order: Order,
+    scale: Option<f64>,
+}
+
+impl Encoding {
+    /// Creates an encoding for a fixed-size type, STRING length is set to a single register
+    pub fn new(tp: DataType) -> Self {
+        Self {
+            tp,
+            registers: tp.size().unwrap_or(1),
+            byte_order: Order::Big,
+            word_order: Order::Big,
+            scale: None,
+        }
+    }
+    /// Sets STRING length (in registers), ignored for other types
+    pub fn string_len(mut self, registers: u16) -> Self {
+        if self.tp == DataType::String {
+            self.registers = registers;
+        }
+        self
+    }
+    pub fn byte_order(mut self, order: Order) -> Self {
+        self.byte_order = order;
+        self
+    }
+    pub fn word_order(mut self, order: Order) -> Self {
+        self.word_order = order;
+        self
+    }
+    /// Numeric values are returned as raw * scale and written as value / scale
+    pub fn scale(mut self, scale: Option<f64>) -> Self {
+        self.scale = scale;
+        self
+    }
+    #[inline]
+    pub fn data_type(&self) -> DataType {
+        self.tp
+    }
+    #[inline]
+    pub fn registers(&self) -> u16 {
+        self.registers
+    }
+    fn words_swapped(&self) -> bool {
+        self.tp != DataType::String
+            && ((self.word_order == Order::Little) ^ (self.tp == DataType::Real))
+    }
+    fn to_bytes(&self, regs: &[u16]) -> Vec<u8> {
+        let mut words = regs.to_vec();
+        if self.words_swapped() {
+            words.reverse();
+        }
+        words
+            .into_iter()
+            .flat_map(|w| match self.byte_order {
+                Order::Big => w.to_be_bytes(),
+                Order::Little => w.to_le_bytes(),
+            })
+            .collect()
+    }
+    fn to_words(&self, bytes: &[u8]) -> Vec<u16> {
+        let mut words: Vec<u16> = bytes
+            .chunks(2)
+            .map(|c| match self.byte_order {
+                Order::Big => u16::from_be_bytes([c[0], c[1]]),
+                Order::Little => u16::from_le_bytes([c[0], c[1]]),
+            })
+            .collect();
+        if self.words_swapped() {
+            words.reverse();
+        }
+        words
+    }
+    /// Decodes the value from registers
+    #[allow(clippy::cast_precision_loss)]
+    pub fn decode(&self, regs: &[u16]) -> EResult<Value> {
+        if regs.len() != usize::from(self.registers) {
+            return Err(Error::invalid_data(format!(
+                "{} registers required",
+                self.registers
+            )));
+        }
+        let b = self.to_bytes(regs);
+        macro_rules! num {
+            ($t: ty, $v: ident) => {{
+                let val = <$t>::from_be_bytes(b.as_slice().try_into().unwrap());
+                if let Some(scale) = self.scale {
+                    Value::F64(val as f64 * scale)
+                } else {
+                    Value::$v(val)
+                }
+            }};
+        }
+        Ok(match self.tp {
+            DataType::Int => num!(i16, I16),
+            DataType::Uint => num!(u16, U16),
+            DataType::Dint => num!(i32, I32),
+            DataType::Udint => num!(u32, U32),
+            DataType::Lint => num!(i64, I64),
+            DataType::Ulint => num!(u64, U64),
+            DataType::Real | DataType::Realb => {
+                let val = f32::from_be_bytes(b.as_slice().try_into().unwrap());
+                if let Some(scale) = self.scale {
+                    Value::F64(f64::from(val) * scale)
+                } else {
+                    Value::F32(val)
+                }
+            }
+            DataType::String => {
+                let len = b.iter().position(|v| *v == 0).unwrap_or(b.len());
+                Value::String(String::from_utf8_lossy(&b[..len]).into_owned())
+            }
+        })
+    }
+    /// Encodes the value into registers
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    pub fn encode(&self, value: Value) -> EResult<Vec<u16>> {
+        macro_rules! num {
+            ($t: ty) => {{
+                let val: $t = if let Some(scale) = self.scale {
+                    let v: f64 = value.try_into()?;
+                    let raw = (v / scale).round();
+                    if !raw.is_finite() || raw < <$t>::MIN as f64 || raw > <$t>::MAX as f64 {
+                        return Err(Error::invalid_data(format!("value out of range: {}", v)));
+                    }
+                    raw as $t
+                } else {
+                    value.try_into()?
+                };
+                val.to_be_bytes().to_vec()
+            }};
+        }
+        let b = match self.tp {
+            DataType::Int => num!(i16),
+            DataType::Uint => num!(u16),
+            DataType::Dint => num!(i32),
+            DataType::Udint => num!(u32),
+            DataType::Lint => num!(i64),
+            DataType::Ulint => num!(u64),
+            DataType::Real | DataType::Realb => {
+                let val: f32 = if let Some(scale) = self.scale {
+                    let v: f64 = value.try_into()?;
+                    (v / scale) as f32
+                } else {
+                    value.try_into()?
+                };
+                val.to_be_bytes().to_vec()
+            }
+            DataType::String => {
+                let s: String = value.try_into()?;
+                let mut b = s.into_bytes();
+                let len = usize::from(self.registers) * 2;
+                if b.len() > len {
+                    return Err(Error::invalid_data(format!(
+                        "string too long, max {} bytes",
+                        len
+                    )));
+                }
+                b.resize(len, 0);
+                b
+            }
+        };
+        Ok(self.to_words(&b))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DataType, Encoding, Order};
+    use eva_common::value::Value;
+
+    #[test]
+    fn encoding() {
+        let enc = Encoding::new(DataType::Realb);
+        let regs = enc.encode(Value::F32(1.5)).unwrap();
+        assert_eq!(regs, [0x3fc0, 0]);
+        assert_eq!(enc.decode(&regs).unwrap(), Value::F32(1.5));
+        let enc = Encoding::new(DataType::Real);
+        assert_eq!(enc.encode(Value::F32(1.5)).unwrap(), [0, 0x3fc0]);
+        let enc = Encoding::new(DataType::Dint).word_order(Order::Little);
+        assert_eq!(enc.encode(Value::I32(-2)).unwrap(), [0xfffe, 0xffff]);
+        let enc = Encoding::new(DataType::Int).scale(Some(0.5));
+        assert_eq!(enc.encode(Value::F64(-12.5)).unwrap(), [0xffe7]);
+        assert_eq!(enc.decode(&[0xffe7]).unwrap(), Value::F64(-12.5));
+        assert!(enc.encode(Value::F64(20000.0)).is_err());
+        let enc = Encoding::new(DataType::String)
+            .string_len(3)
+            .byte_order(Order::Little);
+        let regs = enc.encode(Value::String("abc".to_owned())).unwrap();
+        assert_eq!(regs, [0x6261, 0x0063, 0]);
+        assert_eq!(enc.decode(&regs).unwrap(), Value::String("abc".to_owned()));
+        assert!(enc.encode(Value::String("abcdefg".to_owned())).is_err());
+    }
+}
@@ -0,0 +1,518 @@
+//! Function codes, not covered by rmodbus
+use crate::identity::{Identity, READ_INDIVIDUAL};
+use rmodbus::server::context::ModbusContext;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+pub(crate) const FC_READ_EXCEPTION_STATUS: u8 = 0x07;
+pub(crate) const FC_DIAGNOSTICS: u8 = 0x08;
+pub(crate) const FC_REPORT_SERVER_ID: u8 = 0x11;
+pub(crate) const FC_READ_FILE_RECORD: u8 = 0x14;
+pub(crate) const FC_WRITE_FILE_RECORD: u8 = 0x15;
+pub(crate) const FC_MASK_WRITE_REGISTER: u8 = 0x16;
+pub(crate) const FC_READ_FIFO_QUEUE: u8 = 0x18;
+pub(crate) const FC_ENCAPSULATED: u8 = 0x2B;
+
+const MEI_READ_DEVICE_ID: u8 = 0x0E;
+
+pub(crate) const EXCEPTION_ILLEGAL_FUNCTION: u8 = 0x01;
+pub(crate) const EXCEPTION_ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+pub(crate) const EXCEPTION_ILLEGAL_DATA_VALUE: u8 = 0x03;
+
+const MAX_PDU_LEN: usize = 253;
+const FILE_REFERENCE_TYPE: u8 = 6;
+const FILE_RECORDS: u16 = 10_000;
+pub(crate) const FIFO_MAX_COUNT: u16 = 31;
+const RUN_INDICATOR_ON: u8 = 0xff;
+
+type ExtResult = Result<Vec<u8>, u8>;
+
+#[inline]
+pub(crate) fn is_extended(func: u8) -> bool {
+    matches!(
+        func,
+        FC_READ_EXCEPTION_STATUS
+            | FC_DIAGNOSTICS
+            | FC_REPORT_SERVER_ID
+            | FC_READ_FILE_RECORD
+            | FC_WRITE_FILE_RECORD
+            | FC_MASK_WRITE_REGISTER
+            | FC_READ_FIFO_QUEUE
+            | FC_ENCAPSULATED
+    )
+}
+
+#[inline]
+pub(crate) fn is_write(func: u8) -> bool {
+    matches!(func, FC_WRITE_FILE_RECORD | FC_MASK_WRITE_REGISTER)
+}
+
+/// Serial line diagnostic counters (FC 0x08)
+#[derive(Serialize, Default, Clone)]
+pub struct Diagnostics {
+    pub bus_message: u16,
+    pub bus_communication_error: u16,
+    pub bus_exception_error: u16,
+    pub server_message: u16,
+    pub server_no_response: u16,
+    pub server_nak: u16,
+    pub server_busy: u16,
+    pub bus_character_overrun: u16,
+    pub register: u16,
+    #[serde(skip)]
+    ascii_delimiter: u8,
+}
+
+impl Diagnostics {
+    pub(crate) fn message(&mut self) {
+        self.bus_message = self.bus_message.wrapping_add(1);
+        self.server_message = self.server_message.wrapping_add(1);
+    }
+    pub(crate) fn exception(&mut self) {
+        self.bus_exception_error = self.bus_exception_error.wrapping_add(1);
+    }
+    pub(crate) fn no_response(&mut self) {
+        self.server_no_response = self.server_no_response.wrapping_add(1);
+    }
+    fn clear(&mut self) {
+        *self = Self {
+            ascii_delimiter: self.ascii_delimiter,
+            ..Self::default()
+        };
+    }
+}
+
+/// Extended function state of a unit
+#[derive(Default)]
+pub(crate) struct State {
+    pub(crate) diag: Diagnostics,
+    /// set by FC 0x08/0x04, the unit does not respond until communications are restarted
+    pub(crate) listen_only: bool,
+    /// file records, by file number
+    files: BTreeMap<u16, Vec<u16>>,
+}
+
+#[inline]
+fn word(pdu: &[u8], pos: usize) -> u16 {
+    u16::from_be_bytes([pdu[pos], pdu[pos + 1]])
+}
+
+/// Processes the request PDU, returns the response PDU or the exception code
+///
+/// An empty response PDU means the request must not be replied
+pub(crate) fn process<const C: usize, const D: usize, const I: usize, const H: usize>(
+    pdu: &[u8],
+    ctx: &mut ModbusContext<C, D, I, H>,
+    state: &mut State,
+    identity: &Identity,
+) -> ExtResult {
+    match pdu[0] {
+        FC_READ_EXCEPTION_STATUS => read_exception_status(pdu, ctx),
+        FC_DIAGNOSTICS => diagnostics(pdu, state),
+        FC_REPORT_SERVER_ID => report_server_id(pdu, identity),
+        FC_READ_FILE_RECORD => read_file_record(pdu, &state.files),
+        FC_WRITE_FILE_RECORD => write_file_record(pdu, &mut state.files),
+        FC_MASK_WRITE_REGISTER => mask_write_register(pdu, ctx),
+        FC_READ_FIFO_QUEUE => read_fifo_queue(pdu, ctx),
+        FC_ENCAPSULATED => encapsulated(pdu, identity),
+        _ => Err(EXCEPTION_ILLEGAL_FUNCTION),
+    }
+}
+
+/// The exception status is mapped to coils 0-7
+fn read_exception_status<const C: usize, const D: usize, const I: usize, const H: usize>(
+    pdu: &[u8],
+    ctx: &ModbusContext<C, D, I, H>,
+) -> ExtResult {
+    if pdu.len() != 1 {
+        return Err(EXCEPTION_ILLEGAL_DATA_VALUE);
+    }
+    let mut status = 0_u8;
+    for i in 0..8 {
+        if ctx.get_coil(i).unwrap_or_default() {
+            status |= 1 << i;
+        }
+    }
+    Ok(vec![FC_READ_EXCEPTION_STATUS, status])
+}
+
+/// Restarts communications if the listen-only mode is on, the request is not replied then
+pub(crate) fn is_restart(pdu: &[u8]) -> bool {
+    pdu.len() == 5 && pdu[0] == FC_DIAGNOSTICS && word(pdu, 1) == 0x01
+}
+
+fn diagnostics(pdu: &[u8], state: &mut State) -> ExtResult {
+    let diag = &mut state.diag;
+    if pdu.len() < 3 {
+        return Err(EXCEPTION_ILLEGAL_DATA_VALUE);
+    }
+    let sub_function = word(pdu, 1);
+    // return query data echoes any data
+    if sub_function == 0x00 {
+        return Ok(pdu.to_vec());
+    }
+    if pdu.len() != 5 {
+        return Err(EXCEPTION_ILLEGAL_DATA_VALUE);
+    }
+    let data = word(pdu, 3);
+    if matches!(sub_function, 0x02 | 0x04 | 0x0a..=0x12 | 0x14) && data != 0 {
+        return Err(EXCEPTION_ILLEGAL_DATA_VALUE);
+    }
+    let value = match sub_function {
+        // restart communications option
+        0x01 => {
+            if data != 0x0000 && data != 0xff00 {
+                return Err(EXCEPTION_ILLEGAL_DATA_VALUE);
+            }
+            diag.clear();
+            if state.listen_only {
+                state.listen_only = false;
+                return Ok(Vec::new());
+            }
+            return Ok(pdu.to_vec());
+        }
+        0x02 => diag.register,
+        // change ASCII input delimiter
+        0x03 => {
+            if pdu[4] != 0 {
+                return Err(EXCEPTION_ILLEGAL_DATA_VALUE);
+            }
+            diag.ascii_delimiter = pdu[3];
+            return Ok(pdu.to_vec());
+        }
+        // force listen only mode, not replied
+        0x04 => {
+            state.listen_only = true;
+            return Ok(Vec::new());
+        }
+        0x0a => {
+            diag.clear();
+            0
+        }
+        0x0b => diag.bus_message,
+        0x0c => diag.bus_communication_error,
+        0x0d => diag.bus_exception_error,
+        0x0e => diag.server_message,
+        0x0f => diag.server_no_response,
+        0x10 => diag.server_nak,
+        0x11 => diag.server_busy,
+        0x12 => diag.bus_character_overrun,
+        0x14 => {
+            diag.bus_character_overrun = 0;
+            0
+        }
+        _ => return Err(EXCEPTION_ILLEGAL_FUNCTION),
+    };
+    let mut response = pdu[..3].to_vec();
+    response.extend(value.to_be_bytes());
+    Ok(response)
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn report_server_id(pdu: &[u8], identity: &Identity) -> ExtResult {
+    if pdu.len() != 1 {
+        return Err(EXCEPTION_ILLEGAL_DATA_VALUE);
+    }
+    let id = identity.server_id().as_bytes();
+    let id = &id[..id.len().min(MAX_PDU_LEN - 3)];
+    let mut response = vec![FC_REPORT_SERVER_ID, id.len() as u8 + 1];
+    response.extend(id);
+    response.push(RUN_INDICATOR_ON);
+    Ok(response)
+}
+
+/// Parses file sub-requests: file number, record number, record length and the data offset
+fn file_sub_requests(pdu: &[u8], with_data: bool) -> Result<Vec<(u16, u16, u16, usize)>, u8> {
+    if pdu.len() < 2 || usize::from(pdu[1]) != pdu.len() - 2 || pdu[1] < 7 || pdu[1] > 0xf5 {
+        return Err(EXCEPTION_ILLEGAL_DATA_VALUE);
+    }
+    let mut result = Vec::new();
+    let mut pos = 2;
+    while pos < pdu.len() {
+        if pos + 7 > pdu.len() {
+            return Err(EXCEPTION_ILLEGAL_DATA_VALUE);
+        }
+        if pdu[pos] != FILE_REFERENCE_TYPE {
+            return Err(EXCEPTION_ILLEGAL_DATA_ADDRESS);
+        }
+        let (file, record, len) = (word(pdu, pos + 1), word(pdu, pos + 3), word(pdu, pos + 5));
+        if file == 0
+            || record >= FILE_RECORDS
+            || u32::from(record) + u32::from(len) > u32::from(FILE_RECORDS)
+        {
+            return Err(EXCEPTION_ILLEGAL_DATA_ADDRESS);
+        }
+        result.push((file, record, len, pos + 7));
+        pos += 7;
+        if with_data {
+            pos += usize::from(len) * 2;
+            if pos > pdu.len() {
+                return Err(EXCEPTION_ILLEGAL_DATA_VALUE);
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn read_file_record(pdu: &[u8], files: &BTreeMap<u16, Vec<u16>>) -> ExtResult {
+    let mut response = vec![FC_READ_FILE_RECORD, 0];
+    for (file, record, len, _) in file_sub_requests(pdu, false)? {
+        if response.len() + 2 + usize::from(len) * 2 > MAX_PDU_LEN {
+            return Err(EXCEPTION_ILLEGAL_DATA_VALUE);
+        }
+        response.push((usize::from(len) * 2 + 1) as u8);
+        response.push(FILE_REFERENCE_TYPE);
+        let data = files.get(&file);
+        for r in record..record + len {
+            let value = data
+                .and_then(|d| d.get(usize::from(r)))
+                .copied()
+                .unwrap_or_default();
+            response.extend(value.to_be_bytes());
+        }
+    }
+    response[1] = (response.len() - 2) as u8;
+    Ok(response)
+}
+
+fn write_file_record(pdu: &[u8], files: &mut BTreeMap<u16, Vec<u16>>) -> ExtResult {
+    for (file, record, len, pos) in file_sub_requests(pdu, true)? {
+        let data = files.entry(file).or_default();
+        let end = usize::from(record) + usize::from(len);
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        for i in 0..usize::from(len) {
+            data[usize::from(record) + i] = word(pdu, pos + i * 2);
+        }
+    }
+    Ok(pdu.to_vec())
+}
+
+fn mask_write_register<const C: usize, const D: usize, const I: usize, const H: usize>(
+    pdu: &[u8],
+    ctx: &mut ModbusContext<C, D, I, H>,
+) -> ExtResult {
+    if pdu.len() != 7 {
+        return Err(EXCEPTION_ILLEGAL_DATA_VALUE);
+    }
+    let (reg, and_mask, or_mask) = (word(pdu, 1), word(pdu, 3), word(pdu, 5));
+    let current = ctx
+        .get_holding(reg)
+        .map_err(|_| EXCEPTION_ILLEGAL_DATA_ADDRESS)?;
+    ctx.set_holding(reg, (current & and_mask) | (or_mask & !and_mask))
+        .map_err(|_| EXCEPTION_ILLEGAL_DATA_ADDRESS)?;
+    Ok(pdu.to_vec())
+}
+
+/// The FIFO count is read from the pointer register, followed by the queue registers
+fn read_fifo_queue<const C: usize, const D: usize, const I: usize, const H: usize>(
+    pdu: &[u8],
+    ctx: &ModbusContext<C, D, I, H>,
+) -> ExtResult {
+    if pdu.len() != 3 {
+        return Err(EXCEPTION_ILLEGAL_DATA_VALUE);
+    }
+    let reg = word(pdu, 1);
+    let count = ctx
+        .get_holding(reg)
+        .map_err(|_| EXCEPTION_ILLEGAL_DATA_ADDRESS)?;
+    if count > FIFO_MAX_COUNT {
+        return Err(EXCEPTION_ILLEGAL_DATA_VALUE);
+    }
+    let mut values: Vec<u16> = Vec::with_capacity(usize::from(count));
+    if count > 0 {
+        ctx.get_holdings_bulk(reg.wrapping_add(1), count, &mut values)
+            .map_err(|_| EXCEPTION_ILLEGAL_DATA_ADDRESS)?;
+    }
+    let mut response = vec![FC_READ_FIFO_QUEUE];
+    response.extend((count * 2 + 2).to_be_bytes());
+    response.extend(count.to_be_bytes());
+    for v in values {
+        response.extend(v.to_be_bytes());
+    }
+    Ok(response)
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn encapsulated(pdu: &[u8], identity: &Identity) -> ExtResult {
+    if pdu.len() < 2 || pdu[1] != MEI_READ_DEVICE_ID {
+        return Err(EXCEPTION_ILLEGAL_FUNCTION);
+    }
+    if pdu.len() != 4 {
+        return Err(EXCEPTION_ILLEGAL_DATA_VALUE);
+    }
+    let (code, object_id) = (pdu[2], pdu[3]);
+    let mut response = vec![
+        FC_ENCAPSULATED,
+        MEI_READ_DEVICE_ID,
+        code,
+        identity.conformity_level(),
+        0,
+        0,
+        0,
+    ];
+    let push = |response: &mut Vec<u8>, id: u8, value: &str| {
+        let value = value.as_bytes();
+        let value = &value[..value.len().min(MAX_PDU_LEN - response.len() - 2)];
+        response.push(id);
+        response.push(value.len() as u8);
+        response.extend(value);
+    };
+    if code == READ_INDIVIDUAL {
+        let value = identity
+            .object(object_id)
+            .ok_or(EXCEPTION_ILLEGAL_DATA_ADDRESS)?;
+        push(&mut response, object_id, value);
+        response[6] = 1;
+        return Ok(response);
+    }
+    let range = identity
+        .category(code)
+        .ok_or(EXCEPTION_ILLEGAL_DATA_VALUE)?;
+    // restart from the beginning of the category if the object is unknown
+    let start = if range.contains(&object_id) && identity.object(object_id).is_some() {
+        object_id
+    } else {
+        *range.start()
+    };
+    let mut count = 0;
+    for id in start..=*range.end() {
+        let Some(value) = identity.object(id) else {
+            continue;
+        };
+        if count > 0 && response.len() + 2 + value.len() > MAX_PDU_LEN {
+            response[4] = 0xff;
+            response[5] = id;
+            break;
+        }
+        push(&mut response, id, value);
+        count += 1;
+    }
+    response[6] = count;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encapsulated, process, State};
+    use crate::identity::Identity;
+    use eva_common::value::to_value;
+    use rmodbus::server::context::ModbusContext;
+    use serde::{Deserialize, Serialize};
+
+    #[test]
+    fn functions() {
+        let mut ctx: ModbusContext<8, 0, 0, 100> = ModbusContext::new();
+        let mut state = State::default();
+        let identity = Identity::default();
+        let mut call = |ctx: &mut ModbusContext<8, 0, 0, 100>, pdu: &[u8]| {
+            process(pdu, ctx, &mut state, &identity)
+        };
+        ctx.set_coil(1, true).unwrap();
+        assert_eq!(call(&mut ctx, &[0x07]).unwrap(), [0x07, 0x02]);
+        // mask write: (0x12 & 0xf2) | (0x25 & !0xf2) = 0x17
+        ctx.set_holding(4, 0x12).unwrap();
+        call(&mut ctx, &[0x16, 0, 4, 0, 0xf2, 0, 0x25]).unwrap();
+        assert_eq!(ctx.get_holding(4).unwrap(), 0x17);
+        assert_eq!(
+            call(&mut ctx, &[0x16, 0, 100, 0, 0, 0, 0]).unwrap_err(),
+            0x02
+        );
+        // FIFO
+        ctx.set_holdings_bulk(10, &[2, 0x1b8, 0x1284]).unwrap();
+        assert_eq!(
+            call(&mut ctx, &[0x18, 0, 10]).unwrap(),
+            [0x18, 0, 6, 0, 2, 0x01, 0xb8, 0x12, 0x84]
+        );
+        // file records
+        call(
+            &mut ctx,
+            &[
+                0x15, 0x0d, 6, 0, 4, 0, 7, 0, 3, 0x06, 0xaf, 0x04, 0xbe, 0x10, 0x0d,
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            call(&mut ctx, &[0x14, 0x07, 6, 0, 4, 0, 8, 0, 2]).unwrap(),
+            [0x14, 0x06, 0x05, 6, 0x04, 0xbe, 0x10, 0x0d]
+        );
+        assert_eq!(
+            call(&mut ctx, &[0x14, 0x07, 6, 0, 0, 0, 8, 0, 2]).unwrap_err(),
+            0x02
+        );
+        // diagnostics
+        assert_eq!(
+            call(&mut ctx, &[0x08, 0, 0, 0xa5, 0x37]).unwrap(),
+            [0x08, 0, 0, 0xa5, 0x37]
+        );
+        assert_eq!(call(&mut ctx, &[0x08, 0, 0x99, 0, 0]).unwrap_err(), 0x01);
+        // listen only mode, the restart is not replied
+        assert!(call(&mut ctx, &[0x08, 0, 0x04, 0, 0]).unwrap().is_empty());
+        assert!(call(&mut ctx, &[0x08, 0, 0x01, 0, 0]).unwrap().is_empty());
+        assert_eq!(
+            call(&mut ctx, &[0x08, 0, 0x01, 0, 0]).unwrap(),
+            [0x08, 0, 0x01, 0, 0]
+        );
+        // device identification, basic stream and individual access
+        let response = call(&mut ctx, &[0x2b, 0x0e, 1, 0]).unwrap();
+        assert_eq!(response[..7], [0x2b, 0x0e, 1, 0x82, 0, 0, 3]);
+        assert_eq!(response[7..9], [0, 18]);
+        let response = call(&mut ctx, &[0x2b, 0x0e, 4, 1]).unwrap();
+        assert_eq!(
+            response[6..],
+            [1, 1, 7, b'E', b'V', b'A', b'-', b'S', b'I', b'M']
+        );
+        assert_eq!(call(&mut ctx, &[0x2b, 0x0e, 4, 5]).unwrap_err(), 0x02);
+        let response = call(&mut ctx, &[0x11]).unwrap();
+        assert_eq!(response[..3], [0x11, 8, b'E']);
+        assert_eq!(response[response.len() - 1], 0xff);
+    }
+
+    #[derive(Serialize)]
+    struct ExtendedObject {
+        id: u8,
+        value: String,
+    }
+
+    #[derive(Serialize)]
+    struct IdentityConfig {
+        product_code: String,
+        extended: Vec<ExtendedObject>,
+    }
+
+    #[test]
+    fn device_identification() {
+        let config = IdentityConfig {
+            product_code: "X".to_owned(),
+            extended: (0x80..0x84)
+                .map(|id| ExtendedObject {
+                    id,
+                    value: "v".repeat(100),
+                })
+                .collect(),
+        };
+        let identity = Identity::deserialize(to_value(config).unwrap()).unwrap();
+        let response = encapsulated(&[0x2b, 0x0e, 3, 0], &identity).unwrap();
+        // conformity level, more follows, next object id, number of objects
+        assert_eq!(response[3..7], [0x83, 0xff, 0x82, 5]);
+        assert!(response.len() <= 253);
+        let response = encapsulated(&[0x2b, 0x0e, 3, 0x82], &identity).unwrap();
+        assert_eq!(response[3..8], [0x83, 0, 0, 2, 0x82]);
+        let response = encapsulated(&[0x2b, 0x0e, 4, 0x83], &identity).unwrap();
+        assert_eq!(response[3..9], [0x83, 0, 0, 1, 0x83, 100]);
+        assert_eq!(
+            encapsulated(&[0x2b, 0x0e, 3, 0], &Identity::default()).unwrap_err(),
+            0x03
+        );
+        let config = IdentityConfig {
+            product_code: "X".to_owned(),
+            extended: vec![ExtendedObject {
+                id: 0x10,
+                value: String::new(),
+            }],
+        };
+        assert!(Identity::deserialize(to_value(config).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+
+/// Read device ID codes (FC 0x2B/0x0E)
+pub(crate) const READ_BASIC: u8 = 1;
+pub(crate) const READ_REGULAR: u8 = 2;
+pub(crate) const READ_EXTENDED: u8 = 3;
+pub(crate) const READ_INDIVIDUAL: u8 = 4;
+
+/// Regular identification, stream and individual access
+const CONFORMITY_REGULAR: u8 = 0x82;
+/// Extended identification, stream and individual access
+const CONFORMITY_EXTENDED: u8 = 0x83;
+
+const EXTENDED_FIRST: u8 = 0x80;
+
+/// Device identification objects, reported with FC 0x11 and 0x2B/0x0E
+///
+/// Basic (0x00-0x02), regular (0x03-0x06) and extended (0x80-0xFF) objects are supported
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Identity {
+    #[serde(default = "default_vendor_name")]
+    vendor_name: String,
+    #[serde(default = "default_product_code")]
+    product_code: String,
+    #[serde(default = "default_revision")]
+    revision: String,
+    #[serde(default)]
+    vendor_url: Option<String>,
+    #[serde(default)]
+    product_name: Option<String>,
+    #[serde(default)]
+    model_name: Option<String>,
+    #[serde(default)]
+    user_application_name: Option<String>,
+    /// server id, reported with FC 0x11, the product code if not set
+    #[serde(default)]
+    server_id: Option<String>,
+    /// private objects 0x80-0xFF
+    #[serde(default, deserialize_with = "de_extended")]
+    extended: BTreeMap<u8, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExtendedObject {
+    id: u8,
+    value: String,
+}
+
+fn de_extended<'de, D>(deserializer: D) -> Result<BTreeMap<u8, String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let objects: Vec<ExtendedObject> = Vec::deserialize(deserializer)?;
+    let mut result = BTreeMap::new();
+    for obj in objects {
+        if obj.id < EXTENDED_FIRST {
+            return Err(serde::de::Error::custom(format!(
+                "extended object id must be 0x80 or above: {}",
+                obj.id
+            )));
+        }
+        if result.insert(obj.id, obj.value).is_some() {
+            return Err(serde::de::Error::custom(format!(
+                "extended object {} is defined more than once",
+                obj.id
+            )));
+        }
+    }
+    Ok(result)
+}
+
+fn default_vendor_name() -> String {
+    "Bohemia Automation".to_owned()
+}
+
+fn default_product_code() -> String {
+    "EVA-SIM".to_owned()
+}
+
+fn default_revision() -> String {
+    env!("CARGO_PKG_VERSION").to_owned()
+}
+
+impl Default for Identity {
+    fn default() -> Self {
+        Self {
+            vendor_name: default_vendor_name(),
+            product_code: default_product_code(),
+            revision: default_revision(),
+            vendor_url: None,
+            product_name: None,
+            model_name: None,
+            user_application_name: None,
+            server_id: None,
+            extended: BTreeMap::new(),
+        }
+    }
+}
+
+impl Identity {
+    pub(crate) fn server_id(&self) -> &str {
+        self.server_id.as_deref().unwrap_or(&self.product_code)
+    }
+    pub(crate) fn object(&self, id: u8) -> Option<&str> {
+        match id {
+            0 => Some(&self.vendor_name),
+            1 => Some(&self.product_code),
+            2 => Some(&self.revision),
+            3 => self.vendor_url.as_deref(),
+            4 => self.product_name.as_deref(),
+            5 => self.model_name.as_deref(),
+            6 => self.user_application_name.as_deref(),
+            EXTENDED_FIRST.. => self.extended.get(&id).map(String::as_str),
+            _ => None,
+        }
+    }
+    pub(crate) fn conformity_level(&self) -> u8 {
+        if self.extended.is_empty() {
+            CONFORMITY_REGULAR
+        } else {
+            CONFORMITY_EXTENDED
+        }
+    }
+    /// Object ids of the category, requested by the read device ID code
+    pub(crate) fn category(&self, code: u8) -> Option<std::ops::RangeInclusive<u8>> {
+        match code {
+            READ_BASIC => Some(0..=2),
+            READ_REGULAR => Some(0..=6),
+            READ_EXTENDED if !self.extended.is_empty() => Some(0..=0xff),
+            _ => None,
+        }
+    }
+}
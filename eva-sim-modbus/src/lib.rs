@@ -1,89 +1,429 @@
-use busrt::client::AsyncClient;
-use busrt::{rpc::Rpc, Frame, QoS};
+use access::Request;
+use busrt::rpc::{Rpc, RpcClient};
+use busrt::{Frame, QoS};
+use eva_common::payload::{pack, unpack};
+use eva_common::value::Value;
 use eva_common::{EResult, Error};
-use log::error;
+use eva_sdk::service::{safe_rpc_call, svc_is_terminating, svc_wait_core};
+use functions::{Diagnostics, EXCEPTION_ILLEGAL_FUNCTION};
+use log::{error, warn};
 use once_cell::sync::OnceCell;
+use parking_lot::Mutex as SyncMutex;
 use rmodbus::{
     server::{context::ModbusContext, ModbusFrame},
     ModbusFrameBuf, ModbusProto,
 };
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, MutexGuard};
 use uuid::Uuid;
 
-static BUS_TOPIC_IN: OnceCell<String> = OnceCell::new();
-static BUS_TOPIC_OUT: OnceCell<String> = OnceCell::new();
+mod access;
+pub mod data;
+mod functions;
+mod identity;
 
-pub async fn init(port_svc: &str, client: &mut dyn AsyncClient) -> EResult<()> {
-    let bus_topic_in = format!("SVE/{}/bus/in/", port_svc);
-    let bus_topic_out = format!("SVE/{}/bus/out/", port_svc);
-    client
-        .subscribe(&format!("{}#", bus_topic_in), QoS::Processed)
-        .await?;
-    BUS_TOPIC_IN
-        .set(bus_topic_in)
-        .map_err(|_| Error::core("Unable to set BUS_TOPIC_IN"))?;
-    BUS_TOPIC_OUT
-        .set(bus_topic_out)
-        .map_err(|_| Error::core("Unable to set BUS_TOPIC_OUT"))?;
-    Ok(())
+pub use access::{Access, AccessRule, Exception};
+pub use identity::Identity;
+
+const UNIT_PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Broadcast frames are published by the port to the unit 0 topic
+const BROADCAST_UNIT: u8 = 0;
+
+/// Unit id and function code position in MBAP frames
+const MBAP_UNIT_POS: usize = 6;
+const MBAP_FUNC_POS: usize = 7;
+
+#[derive(Serialize)]
+struct UnitPayload {
+    unit: u8,
+}
+
+/// Device behaviour, plugged into [`UnitService`]
+///
+/// The hooks are called with the unit context locked
+pub trait Device<const C: usize, const D: usize, const I: usize, const H: usize>:
+    Send + Sync + 'static
+{
+    /// var.get RPC method, params are [`Value::Unit`] if not specified
+    fn var_get(&self, _ctx: &ModbusContext<C, D, I, H>, _params: Value) -> EResult<Value> {
+        Err(Error::not_implemented("var.get is not supported"))
+    }
+    /// var.set RPC method
+    fn var_set(&self, _ctx: &mut ModbusContext<C, D, I, H>, _params: Value) -> EResult<()> {
+        Err(Error::not_implemented("var.set is not supported"))
+    }
+    /// Called for client write functions, returns an exception code to deny the write
+    fn write_exception(&self, _func: u8, _reg: u16, _count: u16) -> Option<u8> {
+        None
+    }
+    /// Called for client read functions, returns an exception code to deny the read
+    fn read_exception(&self, _func: u8, _reg: u16, _count: u16) -> Option<u8> {
+        None
+    }
 }
 
-/// # Panics
+/// Simulated unit: owns the context and the port subscription
 ///
-/// Will panic if BUS_TOPIC_IN not set
-pub async fn process_modbus_frame<
-    const C: usize,
-    const D: usize,
-    const I: usize,
-    const H: usize,
->(
-    frame: Frame,
-    ctx: &mut ModbusContext<C, D, I, H>,
+/// Multiple units can be hosted by a single service, frames are passed to each until one
+/// accepts them. Broadcast frames are accepted by none, so all units process them
+pub struct UnitService<T, const C: usize, const D: usize, const I: usize, const H: usize>
+where
+    T: Device<C, D, I, H>,
+{
     unit: u8,
-    rpc: &impl Rpc,
-) {
-    if let Some(topic) = frame.topic() {
-        if let Some(cid) = topic.strip_prefix(BUS_TOPIC_IN.get().unwrap()) {
-            match cid.parse::<Uuid>() {
-                Ok(client_id) => {
-                    let mut response = Vec::new();
-                    let mut buf = frame.payload().to_vec();
-                    buf.resize(256, 0);
-                    let frame_buf: ModbusFrameBuf = buf.try_into().unwrap();
-                    let mut frame =
-                        ModbusFrame::new(unit, &frame_buf, ModbusProto::TcpUdp, &mut response);
-                    if let Err(e) = frame.parse() {
-                        error!("client {} frame parse error: {}", client_id, e);
-                        return;
-                    }
-                    if frame.processing_required {
-                        let result = if frame.readonly {
-                            frame.process_read(ctx)
-                        } else {
-                            frame.process_write(ctx)
-                        };
-                        if let Err(e) = result {
-                            error!("client {} frame process error: {}", client_id, e);
-                            return;
-                        }
-                    }
-                    if frame.response_required {
-                        frame.finalize_response().unwrap();
-                        let _ = rpc
-                            .client()
-                            .lock()
-                            .await
-                            .publish(
-                                &format!("{}{}", BUS_TOPIC_OUT.get().unwrap(), client_id),
-                                response.into(),
-                                QoS::Processed,
-                            )
-                            .await;
-                    }
-                }
-                Err(e) => {
-                    error!("invalid incoming topic {}: {}", topic, e);
+    port_svc: String,
+    topic_in: String,
+    topic_broadcast: String,
+    topic_out: String,
+    write_roles: Option<Vec<String>>,
+    ctx: Mutex<ModbusContext<C, D, I, H>>,
+    state: SyncMutex<functions::State>,
+    identity: Identity,
+    access: Vec<AccessRule>,
+    device: T,
+    rpc: OnceCell<Arc<RpcClient>>,
+}
+
+/// Unpacks RPC call params, returns [`Value::Unit`] for empty payloads
+pub fn params(payload: &[u8]) -> EResult<Value> {
+    if payload.is_empty() {
+        Ok(Value::Unit)
+    } else {
+        unpack(payload)
+    }
+}
+
+impl<T, const C: usize, const D: usize, const I: usize, const H: usize> UnitService<T, C, D, I, H>
+where
+    T: Device<C, D, I, H>,
+{
+    pub fn new(port_svc: &str, unit: u8, device: T) -> Self {
+        Self {
+            unit,
+            port_svc: port_svc.to_owned(),
+            topic_in: format!("SVE/{}/bus/in/{}/", port_svc, unit),
+            topic_broadcast: format!("SVE/{}/bus/in/{}/", port_svc, BROADCAST_UNIT),
+            topic_out: format!("SVE/{}/bus/out/", port_svc),
+            write_roles: None,
+            ctx: <_>::default(),
+            state: <_>::default(),
+            identity: <_>::default(),
+            access: Vec::new(),
+            device,
+            rpc: <_>::default(),
+        }
+    }
+    /// Restricts write functions to clients with the listed roles (Modbus/TCP Security)
+    pub fn write_roles(mut self, roles: Option<Vec<String>>) -> Self {
+        self.write_roles = roles;
+        self
+    }
+    /// Sets device identification objects
+    pub fn identity(mut self, identity: Identity) -> Self {
+        self.identity = identity;
+        self
+    }
+    /// Sets register-level access rules for clients
+    pub fn access(mut self, rules: Vec<AccessRule>) -> Self {
+        self.access = rules;
+        self
+    }
+    /// Sets the initial context
+    pub fn context(mut self, ctx: ModbusContext<C, D, I, H>) -> Self {
+        self.ctx = Mutex::new(ctx);
+        self
+    }
+    #[inline]
+    pub fn unit(&self) -> u8 {
+        self.unit
+    }
+    #[inline]
+    pub fn device(&self) -> &T {
+        &self.device
+    }
+    #[inline]
+    pub async fn lock_context(&self) -> MutexGuard<'_, ModbusContext<C, D, I, H>> {
+        self.ctx.lock().await
+    }
+    /// Diagnostic counters (FC 0x08)
+    pub fn diagnostics(&self) -> Diagnostics {
+        self.state.lock().diag.clone()
+    }
+    pub async fn var_get(&self, params: Value) -> EResult<Value> {
+        self.device.var_get(&*self.ctx.lock().await, params)
+    }
+    pub async fn var_set(&self, params: Value) -> EResult<()> {
+        self.device.var_set(&mut *self.ctx.lock().await, params)
+    }
+    /// Subscribes to the frames, routed by the port service to the unit, and broadcasts, and
+    /// keeps the unit registered in the port service until the service is terminating
+    pub async fn start(&self, rpc: Arc<RpcClient>, timeout: Duration) -> EResult<()> {
+        rpc.client()
+            .lock()
+            .await
+            .subscribe_bulk(
+                &[
+                    &format!("{}#", self.topic_in),
+                    &format!("{}#", self.topic_broadcast),
+                ],
+                QoS::Processed,
+            )
+            .await?;
+        self.rpc
+            .set(rpc.clone())
+            .map_err(|_| Error::core("unit service already started"))?;
+        let unit = self.unit;
+        let port_svc = self.port_svc.clone();
+        tokio::spawn(async move {
+            if let Err(e) = svc_wait_core(&rpc, timeout, true).await {
+                error!("unable to register unit {}: {}", unit, e);
+                return;
+            }
+            let payload = pack(&UnitPayload { unit }).unwrap();
+            let mut int = tokio::time::interval(UNIT_PING_INTERVAL);
+            while !svc_is_terminating() {
+                if let Err(e) = safe_rpc_call(
+                    &rpc,
+                    &port_svc,
+                    "unit.ping",
+                    payload.as_slice().into(),
+                    QoS::Processed,
+                    timeout,
+                )
+                .await
+                {
+                    error!("unit {} registration error: {}", unit, e);
                 }
+                int.tick().await;
+            }
+        });
+        Ok(())
+    }
+    /// Unregisters the unit in the port service
+    pub async fn stop(&self, timeout: Duration) {
+        let Some(rpc) = self.rpc.get() else {
+            return;
+        };
+        let Ok(payload) = pack(&UnitPayload { unit: self.unit }) else {
+            return;
+        };
+        let _ = safe_rpc_call(
+            rpc.as_ref(),
+            &self.port_svc,
+            "unit.unregister",
+            payload.as_slice().into(),
+            QoS::Processed,
+            timeout,
+        )
+        .await;
+    }
+    fn write_allowed(&self, role: Option<&str>) -> bool {
+        self.write_roles
+            .as_ref()
+            .is_none_or(|roles| role.is_some_and(|r| roles.iter().any(|v| v == r)))
+    }
+    /// Returns the exception code if access rules deny the request
+    fn access_exception(&self, request: &Request, ip: Option<IpAddr>) -> Option<u8> {
+        self.access
+            .iter()
+            .filter(|r| r.denies(request))
+            .find_map(|r| r.check(ip))
+    }
+    /// Returns the exception code if the device denies the request
+    fn device_exception(&self, func: u8, request: &Request) -> Option<u8> {
+        let (reg, count) = request.range();
+        if request.is_write() {
+            self.device.write_exception(func, reg, count)
+        } else {
+            self.device.read_exception(func, reg, count)
+        }
+    }
+    /// Processes the frame if it is routed to the unit, returns false otherwise
+    ///
+    /// Broadcast frames are processed but false is returned, so other hosted units get them
+    /// as well
+    pub async fn handle_frame(&self, frame: &Frame) -> bool {
+        let Some(topic) = frame.topic() else {
+            return false;
+        };
+        let (cid, broadcast) = if let Some(cid) = topic.strip_prefix(&self.topic_in) {
+            (cid, false)
+        } else if let Some(cid) = topic.strip_prefix(&self.topic_broadcast) {
+            (cid, true)
+        } else {
+            return false;
+        };
+        let accepted = !broadcast;
+        let Some(rpc) = self.rpc.get() else {
+            return accepted;
+        };
+        // the port passes the client id, the peer IP ("-" for serial port clients) and the
+        // client role (Modbus/TCP Security) as topic parts
+        let mut parts = cid.splitn(3, '/');
+        let client_id = match parts.next().unwrap_or_default().parse::<Uuid>() {
+            Ok(v) => v,
+            Err(e) => {
+                error!("invalid incoming topic {}: {}", topic, e);
+                return accepted;
+            }
+        };
+        let ip = parts.next().and_then(|v| v.parse::<IpAddr>().ok());
+        let role = parts.next();
+        let payload = frame.payload();
+        if payload.len() <= MBAP_FUNC_POS {
+            error!("client {} frame too short", client_id);
+            return accepted;
+        }
+        let func = payload[MBAP_FUNC_POS];
+        {
+            let mut state = self.state.lock();
+            state.diag.message();
+            // in the listen-only mode, only the communication restart is processed
+            if state.listen_only && (broadcast || !functions::is_restart(&payload[MBAP_FUNC_POS..]))
+            {
+                state.diag.no_response();
+                return accepted;
+            }
+            if broadcast {
+                state.diag.no_response();
             }
         }
+        if functions::is_extended(func) {
+            // broadcasts are allowed for write functions only
+            if broadcast && !functions::is_write(func) {
+                return accepted;
+            }
+            let response = self.process_extended(payload, role, ip, client_id).await;
+            if !broadcast && response.len() > MBAP_FUNC_POS {
+                self.reply(rpc, client_id, response).await;
+            }
+            return accepted;
+        }
+        let mut response = Vec::new();
+        let mut buf = payload.to_vec();
+        buf.resize(256, 0);
+        let frame_buf: ModbusFrameBuf = buf.try_into().unwrap();
+        let mut frame = ModbusFrame::new(self.unit, &frame_buf, ModbusProto::TcpUdp, &mut response);
+        if let Err(e) = frame.parse() {
+            error!("client {} frame parse error: {}", client_id, e);
+            return accepted;
+        }
+        if frame.processing_required && !frame.readonly && !self.write_allowed(role) {
+            warn!(
+                "client {} write access denied, role: {}",
+                client_id,
+                role.unwrap_or("-")
+            );
+            frame.processing_required = false;
+            frame.error = EXCEPTION_ILLEGAL_FUNCTION;
+        }
+        // single-write functions do not set the count
+        let count = if frame.func == 5 || frame.func == 6 {
+            1
+        } else {
+            frame.count
+        };
+        let request = Request::new(frame.func, frame.reg, count);
+        if let Some(ref request) = request.filter(|_| frame.processing_required) {
+            if let Some(code) = self.access_exception(request, ip) {
+                warn!(
+                    "client {} access denied, function: {}, register: {}",
+                    client_id, frame.func, frame.reg
+                );
+                frame.processing_required = false;
+                frame.error = code;
+            } else if let Some(code) = self.device_exception(frame.func, request) {
+                warn!(
+                    "client {} request denied, function: {}, register: {}",
+                    client_id, frame.func, frame.reg
+                );
+                frame.processing_required = false;
+                frame.error = code;
+            }
+        }
+        if frame.processing_required {
+            let mut ctx = self.ctx.lock().await;
+            let result = if frame.readonly {
+                frame.process_read(&*ctx)
+            } else {
+                frame.process_write(&mut *ctx)
+            };
+            if let Err(e) = result {
+                error!("client {} frame process error: {}", client_id, e);
+                return accepted;
+            }
+        }
+        if frame.response_required {
+            frame.finalize_response().unwrap();
+            if response.get(MBAP_FUNC_POS).is_some_and(|f| f & 0x80 != 0) {
+                self.state.lock().diag.exception();
+            }
+            self.reply(rpc, client_id, response).await;
+        }
+        accepted
+    }
+    /// Processes function codes, not supported by rmodbus, returns the MBAP response, which
+    /// has no PDU if the request must not be replied
+    #[allow(clippy::cast_possible_truncation)]
+    async fn process_extended(
+        &self,
+        payload: &[u8],
+        role: Option<&str>,
+        ip: Option<IpAddr>,
+        client_id: Uuid,
+    ) -> Vec<u8> {
+        let pdu = &payload[MBAP_FUNC_POS..];
+        let func = pdu[0];
+        let request = Request::extended(pdu);
+        let result = if functions::is_write(func) && !self.write_allowed(role) {
+            warn!(
+                "client {} write access denied, role: {}",
+                client_id,
+                role.unwrap_or("-")
+            );
+            Err(EXCEPTION_ILLEGAL_FUNCTION)
+        } else if let Some(code) = request
+            .as_ref()
+            .and_then(|request| self.access_exception(request, ip))
+        {
+            warn!("client {} access denied, function: {}", client_id, func);
+            Err(code)
+        } else if let Some(code) = request
+            .as_ref()
+            .and_then(|request| self.device_exception(func, request))
+        {
+            warn!("client {} request denied, function: {}", client_id, func);
+            Err(code)
+        } else {
+            let mut ctx = self.ctx.lock().await;
+            functions::process(pdu, &mut ctx, &mut self.state.lock(), &self.identity)
+        };
+        let pdu = result.unwrap_or_else(|code| {
+            self.state.lock().diag.exception();
+            vec![func | 0x80, code]
+        });
+        let mut response = Vec::with_capacity(MBAP_FUNC_POS + pdu.len());
+        response.extend(&payload[..2]);
+        response.extend([0, 0]);
+        response.extend((pdu.len() as u16 + 1).to_be_bytes());
+        response.push(payload[MBAP_UNIT_POS]);
+        response.extend(pdu);
+        response
+    }
+    async fn reply(&self, rpc: &RpcClient, client_id: Uuid, response: Vec<u8>) {
+        let _ = rpc
+            .client()
+            .lock()
+            .await
+            .publish(
+                &format!("{}{}", self.topic_out, client_id),
+                response.into(),
+                QoS::Processed,
+            )
+            .await;
     }
 }
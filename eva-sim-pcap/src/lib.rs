@@ -0,0 +1,365 @@
+//! Minimal pcapng writer for simulated fieldbus traffic
+//!
+//! Stream-based (TCP) and datagram-based (UDP) traffic is written with synthetic IP headers
+//! (LINKTYPE_RAW), so the captures can be opened with the Wireshark Modbus/TCP and AMS
+//! dissectors. Serial line traffic is written as-is with LINKTYPE_USER0.
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const BLOCK_SHB: u32 = 0x0A0D_0D0A;
+const BLOCK_IDB: u32 = 0x0000_0001;
+const BLOCK_EPB: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_EPB_FLAGS: u16 = 2;
+const SNAP_LEN: u32 = 0x0004_0000;
+
+const IP_PROTO_TCP: u8 = 6;
+const IP_PROTO_UDP: u8 = 17;
+const TCP_FLAGS_PSH_ACK: u8 = 0x18;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u16)]
+pub enum LinkType {
+    /// Raw IPv4/IPv6 packets
+    Raw = 101,
+    /// Serial line frames
+    User0 = 147,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Direction {
+    /// From a client to the simulator
+    Inbound,
+    /// From the simulator to a client
+    Outbound,
+}
+
+/// Sequence numbers of a synthetic TCP connection
+#[derive(Debug, Clone)]
+pub struct TcpFlow {
+    client: SocketAddr,
+    server: SocketAddr,
+    client_seq: u32,
+    server_seq: u32,
+}
+
+impl TcpFlow {
+    pub fn new(client: SocketAddr, server: SocketAddr) -> Self {
+        Self {
+            client,
+            server,
+            client_seq: 1,
+            server_seq: 1,
+        }
+    }
+}
+
+pub struct PcapWriter<W: Write> {
+    w: W,
+    interfaces: Vec<LinkType>,
+}
+
+impl PcapWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Creates a new writer and writes the section header
+    pub fn new(mut w: W) -> io::Result<Self> {
+        let mut block = Vec::with_capacity(28);
+        block.extend(BLOCK_SHB.to_le_bytes());
+        block.extend(28_u32.to_le_bytes());
+        block.extend(BYTE_ORDER_MAGIC.to_le_bytes());
+        block.extend(1_u16.to_le_bytes());
+        block.extend(0_u16.to_le_bytes());
+        // section length is not specified
+        block.extend((-1_i64).to_le_bytes());
+        block.extend(28_u32.to_le_bytes());
+        w.write_all(&block)?;
+        Ok(Self {
+            w,
+            interfaces: Vec::new(),
+        })
+    }
+    /// Returns the interface id for the link type, writes the interface description if
+    /// required
+    fn interface(&mut self, link_type: LinkType) -> io::Result<u32> {
+        if let Some(pos) = self.interfaces.iter().position(|v| *v == link_type) {
+            return Ok(u32::try_from(pos).unwrap());
+        }
+        let mut block = Vec::with_capacity(20);
+        block.extend(BLOCK_IDB.to_le_bytes());
+        block.extend(20_u32.to_le_bytes());
+        block.extend((link_type as u16).to_le_bytes());
+        block.extend(0_u16.to_le_bytes());
+        block.extend(SNAP_LEN.to_le_bytes());
+        block.extend(20_u32.to_le_bytes());
+        self.w.write_all(&block)?;
+        self.interfaces.push(link_type);
+        Ok(u32::try_from(self.interfaces.len() - 1).unwrap())
+    }
+    fn write_packet(
+        &mut self,
+        link_type: LinkType,
+        direction: Direction,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let interface_id = self.interface(link_type)?;
+        let ts = u64::try_from(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros(),
+        )
+        .unwrap_or_default();
+        let data_len = u32::try_from(data.len()).map_err(|_| io::ErrorKind::InvalidInput)?;
+        let padding = (4 - data.len() % 4) % 4;
+        // fixed fields + data + flags option + end of options
+        let block_len = 32 + data_len + u32::try_from(padding).unwrap() + 8 + 4;
+        let mut block = Vec::with_capacity(usize::try_from(block_len).unwrap());
+        block.extend(BLOCK_EPB.to_le_bytes());
+        block.extend(block_len.to_le_bytes());
+        block.extend(interface_id.to_le_bytes());
+        #[allow(clippy::cast_possible_truncation)]
+        {
+            block.extend(((ts >> 32) as u32).to_le_bytes());
+            block.extend((ts as u32).to_le_bytes());
+        }
+        block.extend(data_len.to_le_bytes());
+        block.extend(data_len.to_le_bytes());
+        block.extend(data);
+        block.resize(block.len() + padding, 0);
+        block.extend(OPT_EPB_FLAGS.to_le_bytes());
+        block.extend(4_u16.to_le_bytes());
+        let flags: u32 = match direction {
+            Direction::Inbound => 1,
+            Direction::Outbound => 2,
+        };
+        block.extend(flags.to_le_bytes());
+        block.extend(OPT_ENDOFOPT.to_le_bytes());
+        block.extend(0_u16.to_le_bytes());
+        block.extend(block_len.to_le_bytes());
+        self.w.write_all(&block)?;
+        self.w.flush()
+    }
+    /// Writes a serial line frame
+    pub fn write_serial(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        self.write_packet(LinkType::User0, direction, data)
+    }
+    /// Writes a TCP segment with synthetic IP/TCP headers
+    pub fn write_tcp(
+        &mut self,
+        flow: &mut TcpFlow,
+        direction: Direction,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let (src, dst, seq, ack) = match direction {
+            Direction::Inbound => (flow.client, flow.server, flow.client_seq, flow.server_seq),
+            Direction::Outbound => (flow.server, flow.client, flow.server_seq, flow.client_seq),
+        };
+        let mut segment = Vec::with_capacity(20 + data.len());
+        segment.extend(src.port().to_be_bytes());
+        segment.extend(dst.port().to_be_bytes());
+        segment.extend(seq.to_be_bytes());
+        segment.extend(ack.to_be_bytes());
+        // data offset: 5 words
+        segment.push(0x50);
+        segment.push(TCP_FLAGS_PSH_ACK);
+        segment.extend(0xffff_u16.to_be_bytes());
+        // checksum (not calculated) and urgent pointer
+        segment.extend([0, 0, 0, 0]);
+        segment.extend(data);
+        #[allow(clippy::cast_possible_truncation)]
+        let len = data.len() as u32;
+        match direction {
+            Direction::Inbound => flow.client_seq = flow.client_seq.wrapping_add(len),
+            Direction::Outbound => flow.server_seq = flow.server_seq.wrapping_add(len),
+        }
+        let packet = ip_packet(src.ip(), dst.ip(), IP_PROTO_TCP, &segment)?;
+        self.write_packet(LinkType::Raw, direction, &packet)
+    }
+    /// Writes a UDP datagram with synthetic IP/UDP headers
+    pub fn write_udp(
+        &mut self,
+        client: SocketAddr,
+        server: SocketAddr,
+        direction: Direction,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let (src, dst) = match direction {
+            Direction::Inbound => (client, server),
+            Direction::Outbound => (server, client),
+        };
+        let len = u16::try_from(data.len() + 8).map_err(|_| io::ErrorKind::InvalidInput)?;
+        let mut datagram = Vec::with_capacity(8 + data.len());
+        datagram.extend(src.port().to_be_bytes());
+        datagram.extend(dst.port().to_be_bytes());
+        datagram.extend(len.to_be_bytes());
+        // checksum is optional for UDP over IPv4
+        datagram.extend([0, 0]);
+        datagram.extend(data);
+        let packet = ip_packet(src.ip(), dst.ip(), IP_PROTO_UDP, &datagram)?;
+        self.write_packet(LinkType::Raw, direction, &packet)
+    }
+}
+
+fn ip_packet(src: IpAddr, dst: IpAddr, proto: u8, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(40 + payload.len());
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let total_len =
+                u16::try_from(20 + payload.len()).map_err(|_| io::ErrorKind::InvalidInput)?;
+            packet.extend([0x45, 0]);
+            packet.extend(total_len.to_be_bytes());
+            // id, flags (don't fragment), fragment offset
+            packet.extend([0, 0, 0x40, 0]);
+            packet.push(64);
+            packet.push(proto);
+            packet.extend([0, 0]);
+            packet.extend(src.octets());
+            packet.extend(dst.octets());
+            let checksum = ip_checksum(&packet);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        _ => {
+            let to_v6 = |addr: IpAddr| match addr {
+                IpAddr::V4(v) => v.to_ipv6_mapped(),
+                IpAddr::V6(v) => v,
+            };
+            let payload_len =
+                u16::try_from(payload.len()).map_err(|_| io::ErrorKind::InvalidInput)?;
+            packet.extend([0x60, 0, 0, 0]);
+            packet.extend(payload_len.to_be_bytes());
+            packet.push(proto);
+            packet.push(64);
+            packet.extend(to_v6(src).octets());
+            packet.extend(to_v6(dst).octets());
+        }
+    }
+    packet.extend(payload);
+    Ok(packet)
+}
+
+fn ip_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|c| u32::from(u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    !(sum as u16)
+}
+
+/// A capture file with synthetic TCP flows, tracked by client keys
+pub struct Capture<K: Ord> {
+    writer: PcapWriter<BufWriter<File>>,
+    path: String,
+    flows: BTreeMap<K, TcpFlow>,
+}
+
+impl<K: Ord> Capture<K> {
+    pub fn create(path: &str) -> io::Result<Self> {
+        Ok(Self {
+            writer: PcapWriter::create(path)?,
+            path: path.to_owned(),
+            flows: BTreeMap::new(),
+        })
+    }
+    #[inline]
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+    pub fn write_tcp(
+        &mut self,
+        key: K,
+        client: SocketAddr,
+        server: SocketAddr,
+        direction: Direction,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let flow = self
+            .flows
+            .entry(key)
+            .or_insert_with(|| TcpFlow::new(client, server));
+        self.writer.write_tcp(flow, direction, data)
+    }
+    #[inline]
+    pub fn write_udp(
+        &mut self,
+        client: SocketAddr,
+        server: SocketAddr,
+        direction: Direction,
+        data: &[u8],
+    ) -> io::Result<()> {
+        self.writer.write_udp(client, server, direction, data)
+    }
+    #[inline]
+    pub fn write_serial(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        self.writer.write_serial(direction, data)
+    }
+    /// Must be called when a client is disconnected
+    #[inline]
+    pub fn remove_flow(&mut self, key: &K) {
+        self.flows.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ip_checksum, Direction, PcapWriter, TcpFlow};
+
+    fn u32_at(buf: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn blocks() {
+        let mut buf = Vec::new();
+        let mut writer = PcapWriter::new(&mut buf).unwrap();
+        let mut flow = TcpFlow::new(
+            "10.0.0.2:40000".parse().unwrap(),
+            "10.0.0.1:502".parse().unwrap(),
+        );
+        writer
+            .write_tcp(&mut flow, Direction::Inbound, &[0, 1, 0, 0, 0, 2, 1, 7])
+            .unwrap();
+        writer
+            .write_serial(Direction::Outbound, &[1, 7, 0])
+            .unwrap();
+        assert_eq!(flow.client_seq, 9);
+        // SHB
+        assert_eq!(u32_at(&buf, 0), 0x0A0D_0D0A);
+        assert_eq!(u32_at(&buf, 4), 28);
+        // IDB, raw
+        assert_eq!(u32_at(&buf, 28), 1);
+        assert_eq!(u16::from_le_bytes([buf[36], buf[37]]), 101);
+        // EPB: 20 bytes IP + 20 bytes TCP + 8 bytes data
+        assert_eq!(u32_at(&buf, 48), 6);
+        let epb_len = u32_at(&buf, 52) as usize;
+        assert_eq!(epb_len, 32 + 48 + 12);
+        assert_eq!(u32_at(&buf, 48 + 20), 48);
+        assert_eq!(u32_at(&buf, 48 + epb_len - 4), epb_len as u32);
+        // IDB, user0, then EPB with interface id 1
+        let pos = 48 + epb_len;
+        assert_eq!(u16::from_le_bytes([buf[pos + 8], buf[pos + 9]]), 147);
+        assert_eq!(u32_at(&buf, pos + 20 + 8), 1);
+    }
+
+    #[test]
+    fn checksum() {
+        let header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(ip_checksum(&header), 0xb861);
+    }
+}
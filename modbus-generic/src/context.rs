@@ -0,0 +1,189 @@
+use crate::tags::Table;
+use eva_common::prelude::*;
+use eva_sim_modbus::data::{DataType, Encoding, Order};
+use rmodbus::server::context::ModbusContextFull;
+use serde::Deserialize;
+
+/// Optional interpretation of register ranges
+#[derive(Deserialize, Default)]
+pub struct ValueType {
+    #[serde(default, rename = "type")]
+    tp: Option<DataType>,
+    /// STRING length, in registers
+    #[serde(default)]
+    length: Option<u16>,
+    #[serde(default)]
+    byte_order: Order,
+    #[serde(default)]
+    word_order: Order,
+}
+
+impl ValueType {
+    fn encoding(&self, table: Table) -> EResult<Option<Encoding>> {
+        let Some(tp) = self.tp else {
+            return Ok(None);
+        };
+        if table.is_bit() {
+            return Err(Error::invalid_params("bit tables can not be typed"));
+        }
+        if self.length == Some(0) {
+            return Err(Error::invalid_params("length must be positive"));
+        }
+        Ok(Some(
+            Encoding::new(tp)
+                .string_len(self.length.unwrap_or(1))
+                .byte_order(self.byte_order)
+                .word_order(self.word_order),
+        ))
+    }
+}
+
+fn range_error(e: rmodbus::ErrorKind) -> Error {
+    Error::invalid_params(format!("invalid range: {}", e))
+}
+
+/// Reads count values, registers are returned as u16 if no type specified
+pub fn get(
+    ctx: &ModbusContextFull,
+    table: Table,
+    address: u16,
+    count: u16,
+    vt: &ValueType,
+) -> EResult<Vec<Value>> {
+    let encoding = vt.encoding(table)?;
+    let regs_count = u16::try_from(
+        u32::from(count) * u32::from(encoding.as_ref().map_or(1, Encoding::registers)),
+    )
+    .map_err(|_| Error::invalid_params("count too large"))?;
+    if table.is_bit() {
+        let mut bits = Vec::with_capacity(usize::from(count));
+        if table == Table::Coil {
+            ctx.get_coils_bulk(address, count, &mut bits)
+        } else {
+            ctx.get_discretes_bulk(address, count, &mut bits)
+        }
+        .map_err(range_error)?;
+        return Ok(bits.into_iter().map(Value::Bool).collect());
+    }
+    let mut regs = Vec::with_capacity(usize::from(regs_count));
+    if table == Table::Holding {
+        ctx.get_holdings_bulk(address, regs_count, &mut regs)
+    } else {
+        ctx.get_inputs_bulk(address, regs_count, &mut regs)
+    }
+    .map_err(range_error)?;
+    if let Some(encoding) = encoding {
+        regs.chunks(usize::from(encoding.registers()))
+            .map(|chunk| encoding.decode(chunk))
+            .collect()
+    } else {
+        Ok(regs.into_iter().map(Value::U16).collect())
+    }
+}
+
+/// Writes values starting from the address, nothing is written on errors
+pub fn set(
+    ctx: &mut ModbusContextFull,
+    table: Table,
+    address: u16,
+    values: Vec<Value>,
+    vt: &ValueType,
+) -> EResult<()> {
+    let encoding = vt.encoding(table)?;
+    if table.is_bit() {
+        let bits = values
+            .into_iter()
+            .map(bool::try_from)
+            .collect::<EResult<Vec<bool>>>()?;
+        return if table == Table::Coil {
+            ctx.set_coils_bulk(address, &bits)
+        } else {
+            ctx.set_discretes_bulk(address, &bits)
+        }
+        .map_err(range_error);
+    }
+    let mut regs = Vec::with_capacity(values.len());
+    for value in values {
+        if let Some(ref encoding) = encoding {
+            regs.extend(encoding.encode(value)?);
+        } else {
+            regs.push(u16::try_from(value)?);
+        }
+    }
+    if table == Table::Holding {
+        ctx.set_holdings_bulk(address, &regs)
+    } else {
+        ctx.set_inputs_bulk(address, &regs)
+    }
+    .map_err(range_error)
+}
+
+/// Zeroes the table or all tables
+pub fn clear(ctx: &mut ModbusContextFull, table: Option<Table>) {
+    match table {
+        Some(Table::Coil) => ctx.clear_coils(),
+        Some(Table::Discrete) => ctx.clear_discretes(),
+        Some(Table::Input) => ctx.clear_inputs(),
+        Some(Table::Holding) => ctx.clear_holdings(),
+        None => ctx.clear_all(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{clear, get, set, ValueType};
+    use crate::tags::Table;
+    use eva_common::value::Value;
+    use eva_sim_modbus::data::DataType;
+    use rmodbus::server::context::ModbusContextFull;
+
+    #[test]
+    fn bulk() {
+        let mut ctx = ModbusContextFull::new();
+        let dint = ValueType {
+            tp: Some(DataType::Dint),
+            ..ValueType::default()
+        };
+        set(
+            &mut ctx,
+            Table::Holding,
+            100,
+            vec![Value::I64(-1), Value::I64(70_000)],
+            &dint,
+        )
+        .unwrap();
+        assert_eq!(
+            get(&ctx, Table::Holding, 100, 4, &ValueType::default()).unwrap(),
+            [
+                Value::U16(0xffff),
+                Value::U16(0xffff),
+                Value::U16(1),
+                Value::U16(4464)
+            ]
+        );
+        assert_eq!(
+            get(&ctx, Table::Holding, 100, 2, &dint).unwrap(),
+            [Value::I32(-1), Value::I32(70_000)]
+        );
+        assert!(get(&ctx, Table::Coil, 0, 1, &dint).is_err());
+        assert!(set(
+            &mut ctx,
+            Table::Input,
+            9999,
+            vec![Value::U16(1); 2],
+            &ValueType::default()
+        )
+        .is_err());
+        set(
+            &mut ctx,
+            Table::Coil,
+            0,
+            vec![Value::Bool(true)],
+            &ValueType::default(),
+        )
+        .unwrap();
+        clear(&mut ctx, Some(Table::Holding));
+        assert_eq!(ctx.get_holding(100).unwrap(), 0);
+        assert!(ctx.get_coil(0).unwrap());
+    }
+}
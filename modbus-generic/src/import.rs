@@ -0,0 +1,351 @@
+use crate::tags::{Table, TagConfig};
+use eva_common::prelude::*;
+use eva_sim_modbus::data::DataType;
+use log::warn;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Imported tags, None unit means the tag is mapped for all hosted units
+pub struct Imported {
+    tags: Vec<(Option<u8>, TagConfig)>,
+}
+
+impl Imported {
+    pub fn for_unit(&self, unit: u8) -> impl Iterator<Item = &TagConfig> {
+        self.tags
+            .iter()
+            .filter(move |(u, _)| u.is_none_or(|u| u == unit))
+            .map(|(_, tag)| tag)
+    }
+}
+
+/// Loads a register map file
+///
+/// CSV files must have a header row with tag field names, YAML/JSON files may contain either
+/// a list of tags or an EVA ICS Modbus controller config (pull and action_map)
+pub async fn load(path: &Path) -> EResult<Imported> {
+    let data = tokio::fs::read_to_string(path).await?;
+    let tags = if path
+        .extension()
+        .is_some_and(|v| v.eq_ignore_ascii_case("csv"))
+    {
+        parse_csv(&data)?
+    } else {
+        parse_yaml(&data)?
+    };
+    Ok(Imported { tags })
+}
+
+fn parse_yaml(data: &str) -> EResult<Vec<(Option<u8>, TagConfig)>> {
+    let mut value: Value = serde_yaml::from_str(data).map_err(Error::invalid_data)?;
+    if let Value::Map(ref mut m) = value {
+        // service deployment file
+        if let Some(config) = m.remove(&Value::String("config".to_owned())) {
+            value = config;
+        }
+    }
+    if let Value::Seq(_) = value {
+        let tags: Vec<TagConfig> = Vec::deserialize(value)?;
+        Ok(tags.into_iter().map(|tag| (None, tag)).collect())
+    } else {
+        controller_tags(ControllerConfig::deserialize(value)?)
+    }
+}
+
+#[derive(Deserialize)]
+struct ControllerConfig {
+    #[serde(default)]
+    pull: Vec<Pull>,
+    #[serde(default)]
+    action_map: BTreeMap<String, ActionMap>,
+}
+
+#[derive(Deserialize)]
+struct Pull {
+    unit: u8,
+    reg: String,
+    #[serde(default)]
+    map: Vec<PullMap>,
+}
+
+#[derive(Deserialize)]
+struct PullMap {
+    #[serde(default)]
+    offset: Value,
+    oid: String,
+    #[serde(default, rename = "type")]
+    tp: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ActionMap {
+    unit: u8,
+    reg: String,
+    #[serde(default, rename = "type")]
+    tp: Option<String>,
+}
+
+/// Parses register references as c0, h@10 etc.
+fn parse_reg(reg: &str) -> EResult<(Table, u16)> {
+    let table = match reg.chars().next() {
+        Some('c') => Table::Coil,
+        Some('d') => Table::Discrete,
+        Some('i') => Table::Input,
+        Some('h') => Table::Holding,
+        _ => return Err(Error::invalid_data(format!("invalid register: {}", reg))),
+    };
+    let addr = reg[1..].trim_start_matches('@');
+    let address = addr
+        .parse()
+        .map_err(|_| Error::invalid_data(format!("invalid register: {}", reg)))?;
+    Ok((table, address))
+}
+
+fn controller_type(tp: Option<&str>, table: Table) -> EResult<Option<DataType>> {
+    if matches!(table, Table::Coil | Table::Discrete) {
+        return Ok(None);
+    }
+    let Some(tp) = tp else {
+        return Ok(Some(DataType::Uint));
+    };
+    Ok(Some(match tp.to_lowercase().as_str() {
+        "int" | "i16" | "int16" | "sint" => DataType::Int,
+        "uint" | "u16" | "uint16" | "word" => DataType::Uint,
+        "dint" | "i32" | "int32" => DataType::Dint,
+        "udint" | "u32" | "uint32" | "dword" => DataType::Udint,
+        "lint" | "i64" | "int64" => DataType::Lint,
+        "ulint" | "u64" | "uint64" | "lword" => DataType::Ulint,
+        "real" | "f32" | "real32" => DataType::Real,
+        "realb" | "real32b" | "f32b" => DataType::Realb,
+        v => {
+            return Err(Error::invalid_data(format!(
+                "unsupported controller type: {}",
+                v
+            )))
+        }
+    }))
+}
+
+fn controller_tags(config: ControllerConfig) -> EResult<Vec<(Option<u8>, TagConfig)>> {
+    let mut tags = Vec::new();
+    for pull in config.pull {
+        let (table, base) = parse_reg(&pull.reg)?;
+        for map in pull.map {
+            let (table, address) = match map.offset {
+                Value::Unit => (table, base),
+                Value::String(ref s) if s.starts_with('=') => parse_reg(&s[1..])?,
+                Value::String(ref s) if s.contains('/') => {
+                    warn!("{}: register bits are not supported, skipped", map.oid);
+                    continue;
+                }
+                v => {
+                    let offset = u16::try_from(v)?;
+                    (
+                        table,
+                        base.checked_add(offset)
+                            .ok_or_else(|| Error::invalid_data("offset out of range"))?,
+                    )
+                }
+            };
+            let tp = controller_type(map.tp.as_deref(), table)?;
+            tags.push((
+                Some(pull.unit),
+                TagConfig::new(&map.oid, table, address, tp),
+            ));
+        }
+    }
+    for (oid, map) in config.action_map {
+        if tags.iter().any(|(_, t)| t.name() == oid) {
+            continue;
+        }
+        if map.reg.contains('/') {
+            warn!("{}: register bits are not supported, skipped", oid);
+            continue;
+        }
+        let (table, address) = parse_reg(&map.reg)?;
+        let tp = controller_type(map.tp.as_deref(), table)?;
+        tags.push((Some(map.unit), TagConfig::new(&oid, table, address, tp)));
+    }
+    Ok(tags)
+}
+
+fn parse_csv_line(line: &str, delimiter: char) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            if c == '"' {
+                if chars.peek() == Some(&'"') {
+                    field.push('"');
+                    chars.next();
+                } else {
+                    quoted = false;
+                }
+            } else {
+                field.push(c);
+            }
+        } else if c == '"' {
+            quoted = true;
+        } else if c == delimiter {
+            fields.push(std::mem::take(&mut field));
+        } else {
+            field.push(c);
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+fn parse_bool(s: &str) -> Option<bool> {
+    match s.to_lowercase().as_str() {
+        "true" | "yes" | "y" | "1" | "x" => Some(true),
+        "false" | "no" | "n" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Converts a CSV cell to the value, expected by the tag field
+///
+/// If decimal_comma is set, floats may have the comma decimal separator
+fn csv_value(column: &str, cell: &str, decimal_comma: bool) -> EResult<Value> {
+    let invalid = || Error::invalid_data(format!("invalid {}: {}", column, cell));
+    let parse_float = |cell: &str| {
+        if decimal_comma {
+            cell.replacen(',', ".", 1).parse::<f64>()
+        } else {
+            cell.parse::<f64>()
+        }
+    };
+    Ok(match column {
+        "address" | "length" => Value::U16(cell.parse().map_err(|_| invalid())?),
+        "unit" => Value::U8(cell.parse().map_err(|_| invalid())?),
+        "scale" => Value::F64(parse_float(cell).map_err(|_| invalid())?),
+        "read_only" => Value::Bool(parse_bool(cell).ok_or_else(invalid)?),
+        "type" => Value::String(cell.to_uppercase()),
+        "table" | "byte_order" | "word_order" => Value::String(cell.to_lowercase()),
+        "value" => {
+            if let Ok(v) = cell.parse::<i64>() {
+                Value::I64(v)
+            } else if let Ok(v) = parse_float(cell) {
+                Value::F64(v)
+            } else if let Some(v) = parse_bool(cell) {
+                Value::Bool(v)
+            } else {
+                Value::String(cell.to_owned())
+            }
+        }
+        _ => Value::String(cell.to_owned()),
+    })
+}
+
+fn parse_csv(data: &str) -> EResult<Vec<(Option<u8>, TagConfig)>> {
+    let mut lines = data
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty() && !l.starts_with('#'));
+    let Some((_, header)) = lines.next() else {
+        return Ok(Vec::new());
+    };
+    // spreadsheets with the comma decimal separator export semicolon-separated files
+    let delimiter = if header.contains(';') && !header.contains(',') {
+        ';'
+    } else {
+        ','
+    };
+    let columns: Vec<String> = parse_csv_line(header, delimiter)
+        .into_iter()
+        .map(|v| v.trim().to_lowercase())
+        .collect();
+    let mut tags = Vec::new();
+    for (n, line) in lines {
+        let mut row = BTreeMap::new();
+        let mut unit = None;
+        for (column, cell) in columns.iter().zip(parse_csv_line(line, delimiter)) {
+            let cell = cell.trim();
+            if cell.is_empty() {
+                continue;
+            }
+            let value = csv_value(column, cell, delimiter == ';')
+                .map_err(|e| Error::invalid_data(format!("line {}: {}", n + 1, e)))?;
+            if column == "unit" {
+                unit = Some(u8::try_from(value)?);
+            } else {
+                row.insert(Value::String(column.clone()), value);
+            }
+        }
+        let tag = TagConfig::deserialize(Value::Map(row))
+            .map_err(|e| Error::invalid_data(format!("line {}: {}", n + 1, e)))?;
+        tags.push((unit, tag));
+    }
+    Ok(tags)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{csv_value, parse_csv, parse_yaml};
+    use eva_common::value::Value;
+
+    #[test]
+    fn csv() {
+        let data = "name;table;address;type;scale;value;unit\n\
+                    # comment\n\
+                    temp;h;10;real;0,1;\"20\";\n\
+                    run;c;1;;;true;2\n";
+        // the semicolon-separated file has comma decimals
+        let tags = parse_csv(data).unwrap();
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags[0].1.name(), "temp");
+        assert_eq!(tags[1].0, Some(2));
+        assert_eq!(csv_value("scale", "0,1", true).unwrap(), Value::F64(0.1));
+        assert_eq!(csv_value("value", "-2,5", true).unwrap(), Value::F64(-2.5));
+        assert!(csv_value("scale", "0,1", false).is_err());
+        assert_eq!(
+            csv_value("value", "a,b", true).unwrap(),
+            Value::String("a,b".to_owned())
+        );
+        let data = "name,table,address,type,scale,value,unit\n\
+                    temp,h,10,real,0.1,20,\n\
+                    \"run, main\",c,1,,,true,2\n";
+        let tags = parse_csv(data).unwrap();
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags[0].0, None);
+        assert_eq!(tags[1].0, Some(2));
+        assert_eq!(tags[1].1.name(), "run, main");
+    }
+
+    #[test]
+    fn controller() {
+        let data = r"
+config:
+  pull:
+    - unit: 1
+      reg: h10
+      count: 4
+      map:
+        - offset: 0
+          oid: sensor:t1
+          type: real
+        - offset: =i5
+          oid: sensor:t2
+        - offset: 3/1
+          oid: sensor:bit
+  action_map:
+    unit:u1:
+      unit: 2
+      reg: c0
+";
+        let tags = parse_yaml(data).unwrap();
+        let names: Vec<_> = tags.iter().map(|(u, t)| (*u, t.name())).collect();
+        assert_eq!(
+            names,
+            [
+                (Some(1), "sensor:t1"),
+                (Some(1), "sensor:t2"),
+                (Some(2), "unit:u1")
+            ]
+        );
+    }
+}
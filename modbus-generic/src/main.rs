@@ -1,21 +1,34 @@
-use busrt::rpc::Rpc;
 use eva_common::prelude::*;
+use eva_common::tools::de_opt_float_as_duration;
 use eva_sdk::prelude::*;
-use once_cell::sync::{Lazy, OnceCell};
+use eva_sim_modbus::{AccessRule, Device, Identity, UnitService};
+use once_cell::sync::OnceCell;
+use parking_lot::RwLock;
 use rmodbus::server::context::ModbusContextFull;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use snapshot::{Format, Snapshot};
+use std::collections::BTreeSet;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tags::{Table, TagConfig, TagMap};
+
+mod context;
+mod import;
+mod snapshot;
+mod tags;
 
 const AUTHOR: &str = "Bohemia Automation";
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const DESCRIPTION: &str = "SIM Virtual Modbus generic context";
 
-static RPC: OnceCell<Arc<RpcClient>> = OnceCell::new();
-static CONTEXT: Lazy<Mutex<ModbusContextFull>> = Lazy::new(<_>::default);
-static DATA_FILE: OnceCell<PathBuf> = OnceCell::new();
+/// Persistent context directory, set if persistent
+static DATA_DIR: OnceCell<PathBuf> = OnceCell::new();
+static SNAPSHOT_DIR: OnceCell<PathBuf> = OnceCell::new();
+static FORMAT: OnceCell<Format> = OnceCell::new();
+
+/// Msgpack context file of a single-unit service before multi-unit hosting
+const LEGACY_DATA_FILE: &str = "ctx.dat";
 
 #[cfg(not(feature = "std-alloc"))]
 #[global_allocator]
@@ -23,9 +36,164 @@ static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
 err_logger!();
 
+type Unit = UnitService<Generic, 10_000, 10_000, 10_000, 10_000>;
+
+/// Raw context with an optional register map
+struct Generic {
+    tags: RwLock<TagMap>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParamsVarGet {
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParamsVarSet {
+    name: String,
+    value: Value,
+}
+
+#[derive(Serialize)]
+struct ValuePayload {
+    value: Value,
+}
+
+impl Device<10_000, 10_000, 10_000, 10_000> for Generic {
+    fn var_get(&self, ctx: &ModbusContextFull, params: Value) -> EResult<Value> {
+        let p = ParamsVarGet::deserialize(params)?;
+        let value = self.tags.read().get(ctx, &p.name)?;
+        Ok(to_value(ValuePayload { value })?)
+    }
+    fn var_set(&self, ctx: &mut ModbusContextFull, params: Value) -> EResult<()> {
+        let p = ParamsVarSet::deserialize(params)?;
+        self.tags.read().set(ctx, &p.name, p.value)
+    }
+    fn write_exception(&self, func: u8, reg: u16, count: u16) -> Option<u8> {
+        self.tags.read().write_exception(func, reg, count)
+    }
+}
+
 struct Handlers {
     info: ServiceInfo,
+    units: Arc<Vec<Unit>>,
+}
+
+impl Handlers {
+    /// Returns the unit, which can be omitted if a single one is hosted
+    fn get_unit(&self, unit: Option<u8>) -> EResult<&Unit> {
+        if let Some(unit_id) = unit {
+            self.units
+                .iter()
+                .find(|u| u.unit() == unit_id)
+                .ok_or_else(|| Error::not_found(format!("unit {} not found", unit_id)))
+        } else if self.units.len() == 1 {
+            Ok(&self.units[0])
+        } else {
+            Err(Error::invalid_params("unit not specified"))
+        }
+    }
+    /// Returns the unit or all units if not specified
+    fn target_units(&self, unit: Option<u8>) -> EResult<Vec<&Unit>> {
+        if unit.is_some() {
+            Ok(vec![self.get_unit(unit)?])
+        } else {
+            Ok(self.units.iter().collect())
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParamsUnit {
+    #[serde(default)]
+    unit: Option<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParamsImport {
+    path: String,
+    #[serde(default)]
+    unit: Option<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParamsSnapshot {
+    name: String,
+    #[serde(default)]
+    unit: Option<u8>,
+}
+
+fn default_count() -> u16 {
+    1
+}
+
+#[derive(Deserialize)]
+struct ParamsCtxGet {
+    #[serde(default)]
+    unit: Option<u8>,
+    table: Table,
+    address: u16,
+    /// number of values (typed or raw)
+    #[serde(default = "default_count")]
+    count: u16,
+    #[serde(flatten)]
+    vt: context::ValueType,
+}
+
+#[derive(Deserialize)]
+struct ParamsCtxSet {
+    #[serde(default)]
+    unit: Option<u8>,
+    table: Table,
+    address: u16,
+    values: Vec<Value>,
+    #[serde(flatten)]
+    vt: context::ValueType,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParamsCtxClear {
+    #[serde(default)]
+    unit: Option<u8>,
+    #[serde(default)]
+    table: Option<Table>,
+}
+
+#[derive(Serialize)]
+struct ImportResult {
     unit: u8,
+    imported: usize,
+}
+
+/// Adds the tags to the unit register map and sets their initial values
+async fn import_tags<'a>(unit: &Unit, tags: impl Iterator<Item = &'a TagConfig>) -> EResult<usize> {
+    let tags: Vec<TagConfig> = tags.cloned().collect();
+    let count = tags.len();
+    let mut ctx = unit.lock_context().await;
+    let mut map = unit.device().tags.read().clone();
+    let initial = map.extend(tags)?;
+    map.set_values(&mut ctx, &initial)?;
+    *unit.device().tags.write() = map;
+    Ok(count)
+}
+
+/// Unpacks call params and takes the unit id out
+fn unit_params(payload: &[u8]) -> EResult<(Option<u8>, Value)> {
+    let mut params = eva_sim_modbus::params(payload)?;
+    let unit = if let Value::Map(ref mut m) = params {
+        m.remove(&Value::String("unit".to_owned()))
+            .map(u8::try_from)
+            .transpose()?
+    } else {
+        None
+    };
+    Ok((unit, params))
 }
 
 #[async_trait::async_trait]
@@ -37,24 +205,170 @@ impl RpcHandlers for Handlers {
         let payload = event.payload();
         match method {
             "save" => {
+                let p: ParamsUnit = if payload.is_empty() {
+                    ParamsUnit { unit: None }
+                } else {
+                    unpack(payload)?
+                };
+                if p.unit.is_some() {
+                    save_context(self.get_unit(p.unit)?).await?;
+                } else {
+                    save_all(&self.units).await?;
+                }
+                Ok(None)
+            }
+            "var.get" => {
+                let (unit, params) = unit_params(payload)?;
+                let value = self.get_unit(unit)?.var_get(params).await?;
+                Ok(Some(pack(&value)?))
+            }
+            "var.set" => {
                 if payload.is_empty() {
-                    save_context().await?;
+                    Err(RpcError::params(None))
+                } else {
+                    let (unit, params) = unit_params(payload)?;
+                    self.get_unit(unit)?.var_set(params).await?;
                     Ok(None)
+                }
+            }
+            "var.list" => {
+                let p: ParamsUnit = if payload.is_empty() {
+                    ParamsUnit { unit: None }
                 } else {
-                    Err(RpcError::params(None))
+                    unpack(payload)?
+                };
+                let unit = self.get_unit(p.unit)?;
+                let ctx = unit.lock_context().await;
+                let tags = unit.device().tags.read();
+                Ok(Some(pack(&tags.list(&ctx)?)?))
+            }
+            "import" => {
+                if payload.is_empty() {
+                    return Err(RpcError::params(None));
+                }
+                let p: ParamsImport = unpack(payload)?;
+                let imported = import::load(Path::new(&p.path)).await?;
+                let units = self.target_units(p.unit)?;
+                let mut result = Vec::with_capacity(units.len());
+                for unit in units {
+                    let count = import_tags(unit, imported.for_unit(unit.unit())).await?;
+                    info!(
+                        "unit {}: {} tags imported from {}",
+                        unit.unit(),
+                        count,
+                        p.path
+                    );
+                    result.push(ImportResult {
+                        unit: unit.unit(),
+                        imported: count,
+                    });
+                }
+                Ok(Some(pack(&result)?))
+            }
+            "snapshot.create" => {
+                if payload.is_empty() {
+                    return Err(RpcError::params(None));
+                }
+                let p: ParamsSnapshot = unpack(payload)?;
+                for unit in self.target_units(p.unit)? {
+                    let path = snapshot_path(unit.unit(), &p.name)?;
+                    let snapshot = Snapshot::from_context(&*unit.lock_context().await);
+                    snapshot::save(&path, &snapshot, format()).await?;
+                    info!("unit {} snapshot {} created", unit.unit(), p.name);
                 }
+                Ok(None)
+            }
+            "snapshot.restore" => {
+                if payload.is_empty() {
+                    return Err(RpcError::params(None));
+                }
+                let p: ParamsSnapshot = unpack(payload)?;
+                // all snapshots are loaded first so a missing/broken one does not restore others
+                let mut contexts = Vec::new();
+                for unit in self.target_units(p.unit)? {
+                    let path = snapshot_path(unit.unit(), &p.name)?;
+                    let snapshot = snapshot::load(&path, format()).await?.ok_or_else(|| {
+                        Error::not_found(format!(
+                            "unit {} snapshot {} not found",
+                            unit.unit(),
+                            p.name
+                        ))
+                    })?;
+                    contexts.push((unit, snapshot.to_context()?));
+                }
+                for (unit, ctx) in contexts {
+                    *unit.lock_context().await = ctx;
+                    info!("unit {} snapshot {} restored", unit.unit(), p.name);
+                }
+                Ok(None)
+            }
+            "ctx.get" => {
+                if payload.is_empty() {
+                    return Err(RpcError::params(None));
+                }
+                let p: ParamsCtxGet = unpack(payload)?;
+                let unit = self.get_unit(p.unit)?;
+                let values = context::get(
+                    &*unit.lock_context().await,
+                    p.table,
+                    p.address,
+                    p.count,
+                    &p.vt,
+                )?;
+                Ok(Some(pack(&values)?))
+            }
+            "ctx.set" => {
+                if payload.is_empty() {
+                    return Err(RpcError::params(None));
+                }
+                let p: ParamsCtxSet = unpack(payload)?;
+                let unit = self.get_unit(p.unit)?;
+                context::set(
+                    &mut *unit.lock_context().await,
+                    p.table,
+                    p.address,
+                    p.values,
+                    &p.vt,
+                )?;
+                Ok(None)
+            }
+            "ctx.clear" => {
+                let p: ParamsCtxClear = if payload.is_empty() {
+                    ParamsCtxClear {
+                        unit: None,
+                        table: None,
+                    }
+                } else {
+                    unpack(payload)?
+                };
+                for unit in self.target_units(p.unit)? {
+                    context::clear(&mut *unit.lock_context().await, p.table);
+                }
+                Ok(None)
+            }
+            "snapshot.list" => {
+                let p: ParamsUnit = if payload.is_empty() {
+                    ParamsUnit { unit: None }
+                } else {
+                    unpack(payload)?
+                };
+                let mut result = Vec::new();
+                for unit in self.target_units(p.unit)? {
+                    result.extend(
+                        snapshot::list(&snapshot_dir(unit.unit())?, unit.unit(), format()).await?,
+                    );
+                }
+                Ok(Some(pack(&result)?))
             }
             _ => svc_handle_default_rpc(method, &self.info),
         }
     }
     async fn handle_frame(&self, frame: Frame) {
-        eva_sim_modbus::process_modbus_frame::<10_000, 10_000, 10_000, 10_000>(
-            frame,
-            &mut *CONTEXT.lock().await,
-            self.unit,
-            RPC.get().unwrap().as_ref(),
-        )
-        .await;
+        for unit in self.units.iter() {
+            if unit.handle_frame(&frame).await {
+                break;
+            }
+        }
     }
 }
 
@@ -62,23 +376,150 @@ impl RpcHandlers for Handlers {
 #[serde(deny_unknown_fields)]
 struct Config {
     port_svc: String,
-    unit: u8,
+    #[serde(default)]
+    unit: Option<u8>,
+    /// hosted units, each one has own context and persistence file
+    #[serde(default)]
+    units: Vec<UnitConfig>,
+    #[serde(default)]
+    write_roles: Option<Vec<String>>,
     #[serde(default)]
     persistent: bool,
+    /// persistent context and snapshot file format
+    #[serde(default)]
+    format: Format,
+    /// persistent context autosave interval
+    #[serde(default, deserialize_with = "de_opt_float_as_duration")]
+    autosave: Option<Duration>,
+    /// device identification objects (FC 0x11, 0x2B/0x0E)
+    #[serde(default)]
+    identity: Identity,
+    /// register-level access rules for clients
+    #[serde(default)]
+    access: Vec<AccessRule>,
+    /// register map
+    #[serde(default)]
+    tags: Vec<TagConfig>,
+    /// register map file (CSV, YAML/JSON tag list or EVA ICS Modbus controller config)
+    #[serde(default)]
+    map_file: Option<String>,
+}
+
+/// Unit ID or unit ID with own identification objects and access rules
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UnitConfig {
+    Id(u8),
+    Full(Box<UnitOptions>),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UnitOptions {
+    unit: u8,
+    #[serde(default)]
+    identity: Option<Identity>,
+    #[serde(default)]
+    access: Option<Vec<AccessRule>>,
+}
+
+impl UnitConfig {
+    fn id(&self) -> u8 {
+        match self {
+            UnitConfig::Id(id) => *id,
+            UnitConfig::Full(u) => u.unit,
+        }
+    }
+    fn options(&self) -> Option<&UnitOptions> {
+        match self {
+            UnitConfig::Id(_) => None,
+            UnitConfig::Full(u) => Some(u),
+        }
+    }
+}
+
+/// Hosted unit, the service-level identity and access rules are used if not set
+struct UnitSetup<'a> {
+    id: u8,
+    identity: &'a Identity,
+    access: &'a [AccessRule],
+}
+
+impl Config {
+    fn unit_setups(&self) -> EResult<Vec<UnitSetup<'_>>> {
+        let mut ids = Vec::with_capacity(self.units.len() + 1);
+        let mut seen = BTreeSet::new();
+        for (unit, options) in self
+            .unit
+            .iter()
+            .map(|u| (*u, None))
+            .chain(self.units.iter().map(|u| (u.id(), u.options())))
+        {
+            if unit == 0 {
+                return Err(Error::invalid_params("broadcast unit can not be hosted"));
+            }
+            if !seen.insert(unit) {
+                return Err(Error::invalid_params(format!(
+                    "unit {} is specified more than once",
+                    unit
+                )));
+            }
+            ids.push(UnitSetup {
+                id: unit,
+                identity: options
+                    .and_then(|o| o.identity.as_ref())
+                    .unwrap_or(&self.identity),
+                access: options
+                    .and_then(|o| o.access.as_deref())
+                    .unwrap_or(&self.access),
+            });
+        }
+        if ids.is_empty() {
+            return Err(Error::invalid_params("no units specified"));
+        }
+        Ok(ids)
+    }
+}
+
+#[inline]
+fn format() -> Format {
+    FORMAT.get().copied().unwrap_or_default()
+}
+
+fn data_file(unit: u8) -> Option<PathBuf> {
+    DATA_DIR
+        .get()
+        .map(|dir| dir.join(format!("ctx{}.{}", unit, format().extension())))
+}
+
+fn snapshot_path(unit: u8, name: &str) -> EResult<PathBuf> {
+    snapshot::validate_name(name)?;
+    Ok(snapshot_dir(unit)?.join(format!("{}.{}", name, format().extension())))
+}
+
+fn snapshot_dir(unit: u8) -> EResult<PathBuf> {
+    SNAPSHOT_DIR
+        .get()
+        .map(|dir| dir.join(unit.to_string()))
+        .ok_or_else(|| Error::failed("snapshots are not available: no data directory"))
+}
+
+async fn save_all(units: &[Unit]) -> EResult<()> {
+    let mut result = Ok(());
+    for unit in units {
+        if let Err(e) = save_context(unit).await {
+            error!("unit {} {}", unit.unit(), e);
+            result = Err(e);
+        }
+    }
+    result
 }
 
-async fn save_context() -> EResult<()> {
-    if let Some(data_file) = DATA_FILE.get() {
-        let mut f = tokio::fs::OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .open(data_file)
-            .await?;
-        let ctx = pack(&*CONTEXT.lock().await)?;
-        f.write_all(&ctx).await?;
-        f.sync_all().await?;
-        info!("context saved");
+async fn save_context(unit: &Unit) -> EResult<()> {
+    if let Some(data_file) = data_file(unit.unit()) {
+        let snapshot = Snapshot::from_context(&*unit.lock_context().await);
+        snapshot::save(&data_file, &snapshot, format()).await?;
+        info!("unit {} context saved", unit.unit());
         Ok(())
     } else {
         Err(Error::failed(
@@ -87,18 +528,36 @@ async fn save_context() -> EResult<()> {
     }
 }
 
-async fn load_context_data() -> Result<Option<Vec<u8>>, std::io::Error> {
-    if let Some(data_file) = DATA_FILE.get() {
-        let mut data = Vec::new();
-        let mut f = tokio::fs::OpenOptions::new()
-            .read(true)
-            .open(data_file)
-            .await?;
-        f.read_to_end(&mut data).await?;
-        Ok(Some(data))
-    } else {
-        Ok(None)
+/// Loads the unit context, a single hosted unit falls back to the legacy msgpack context file
+async fn load_context(unit: &Unit, single: bool) -> EResult<()> {
+    let (Some(dir), Some(data_file)) = (DATA_DIR.get(), data_file(unit.unit())) else {
+        return Ok(());
+    };
+    if let Some(snapshot) = snapshot::load(&data_file, format()).await? {
+        *unit.lock_context().await = snapshot.to_context()?;
+        info!("unit {} context loaded", unit.unit());
+        return Ok(());
     }
+    if single {
+        match tokio::fs::read(dir.join(LEGACY_DATA_FILE)).await {
+            Ok(data) => {
+                *unit.lock_context().await = unpack(&data)?;
+                info!(
+                    "unit {} context loaded from {}",
+                    unit.unit(),
+                    LEGACY_DATA_FILE
+                );
+                return Ok(());
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    info!(
+        "unit {} context not loaded (file not found), empty context created",
+        unit.unit()
+    );
+    Ok(())
 }
 
 #[svc_main]
@@ -108,49 +567,147 @@ async fn main(mut initial: Initial) -> EResult<()> {
             .take_config()
             .ok_or_else(|| Error::invalid_data("config not specified"))?,
     )?;
+    if config.autosave.is_some_and(|v| v.is_zero()) {
+        return Err(Error::invalid_params("autosave interval must be positive"));
+    }
+    let timeout = initial.timeout();
     let mut info = ServiceInfo::new(AUTHOR, VERSION, DESCRIPTION);
-    info.add_method(ServiceMethod::new("save"));
+    info.add_method(ServiceMethod::new("save").optional("unit"));
+    info.add_method(
+        ServiceMethod::new("var.get")
+            .required("name")
+            .optional("unit"),
+    );
+    info.add_method(
+        ServiceMethod::new("var.set")
+            .required("name")
+            .required("value")
+            .optional("unit"),
+    );
+    info.add_method(ServiceMethod::new("var.list").optional("unit"));
+    info.add_method(
+        ServiceMethod::new("import")
+            .required("path")
+            .optional("unit"),
+    );
+    info.add_method(
+        ServiceMethod::new("snapshot.create")
+            .required("name")
+            .optional("unit"),
+    );
+    info.add_method(
+        ServiceMethod::new("snapshot.restore")
+            .required("name")
+            .optional("unit"),
+    );
+    info.add_method(ServiceMethod::new("snapshot.list").optional("unit"));
+    info.add_method(
+        ServiceMethod::new("ctx.get")
+            .required("table")
+            .required("address")
+            .optional("count")
+            .optional("type")
+            .optional("length")
+            .optional("byte_order")
+            .optional("word_order")
+            .optional("unit"),
+    );
+    info.add_method(
+        ServiceMethod::new("ctx.set")
+            .required("table")
+            .required("address")
+            .required("values")
+            .optional("type")
+            .optional("length")
+            .optional("byte_order")
+            .optional("word_order")
+            .optional("unit"),
+    );
+    info.add_method(
+        ServiceMethod::new("ctx.clear")
+            .optional("table")
+            .optional("unit"),
+    );
+    FORMAT
+        .set(config.format)
+        .map_err(|_| Error::core("Unable to set FORMAT"))?;
+    let unit_setups = config.unit_setups()?;
+    let imported = if let Some(ref map_file) = config.map_file {
+        Some(import::load(Path::new(map_file)).await?)
+    } else {
+        None
+    };
+    let units: Arc<Vec<Unit>> = Arc::new(
+        unit_setups
+            .iter()
+            .map(|setup| {
+                Unit::new(
+                    &config.port_svc,
+                    setup.id,
+                    Generic {
+                        tags: <_>::default(),
+                    },
+                )
+                .write_roles(config.write_roles.clone())
+                .identity(setup.identity.clone())
+                .access(setup.access.to_vec())
+            })
+            .collect(),
+    );
+    for unit in units.iter() {
+        import_tags(unit, config.tags.iter()).await?;
+        if let Some(ref imported) = imported {
+            let count = import_tags(unit, imported.for_unit(unit.unit())).await?;
+            info!("unit {}: {} tags imported", unit.unit(), count);
+        }
+    }
     let rpc = initial
         .init_rpc(Handlers {
             info,
-            unit: config.unit,
+            units: units.clone(),
         })
         .await?;
     initial.drop_privileges()?;
     let client = rpc.client().clone();
-    RPC.set(rpc.clone())
-        .map_err(|_| Error::core("Unable to set RPC"))?;
-    eva_sim_modbus::init(&config.port_svc, &mut *client.lock().await).await?;
     svc_init_logs(&initial, client.clone())?;
-    if config.persistent {
-        if let Some(data_path) = initial.data_path() {
-            let mut data_file = Path::new(data_path).to_owned();
-            data_file.push("ctx.dat");
-            DATA_FILE
-                .set(data_file.clone())
-                .map_err(|_| Error::core("Unable to set DATA_FILE"))?;
-            match load_context_data().await {
-                Ok(Some(data)) => {
-                    info!("context loaded");
-                    *CONTEXT.lock().await = unpack(&data)?;
-                }
-                Ok(None) => {}
-                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                    info!("context not loaded (file not found), empty context created");
-                }
-                Err(e) => {
-                    return Err(e.into());
-                }
+    if let Some(data_path) = initial.data_path() {
+        SNAPSHOT_DIR
+            .set(Path::new(data_path).join("snapshots"))
+            .map_err(|_| Error::core("Unable to set SNAPSHOT_DIR"))?;
+        if config.persistent {
+            DATA_DIR
+                .set(Path::new(data_path).to_owned())
+                .map_err(|_| Error::core("Unable to set DATA_DIR"))?;
+            for unit in units.iter() {
+                load_context(unit, units.len() == 1).await?;
+            }
+            if let Some(interval) = config.autosave {
+                let units = units.clone();
+                tokio::spawn(async move {
+                    let mut int = tokio::time::interval(interval);
+                    int.tick().await;
+                    loop {
+                        int.tick().await;
+                        // errors are logged by save_all
+                        let _ = save_all(&units).await;
+                    }
+                });
             }
         }
     }
     svc_start_signal_handlers();
+    for unit in units.iter() {
+        unit.start(rpc.clone(), timeout).await?;
+    }
     svc_mark_ready(&client).await?;
     info!("{} started ({})", DESCRIPTION, initial.id());
     svc_block(&rpc).await;
     svc_mark_terminating(&client).await?;
-    if DATA_FILE.get().is_some() {
-        save_context().await?;
+    for unit in units.iter() {
+        unit.stop(timeout).await;
+    }
+    if DATA_DIR.get().is_some() {
+        save_all(&units).await?;
     }
     Ok(())
 }
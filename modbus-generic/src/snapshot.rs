@@ -0,0 +1,257 @@
+use eva_common::prelude::*;
+use once_cell::sync::Lazy;
+use rmodbus::server::context::{ModbusContextFull, FULL_CONTEXT_SIZE};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// Serializes file writes (autosave, RPC calls), as temporary files have fixed names
+static WRITE_LOCK: Lazy<Mutex<()>> = Lazy::new(<_>::default);
+
+#[allow(clippy::cast_possible_truncation)]
+const CONTEXT_SIZE: u16 = FULL_CONTEXT_SIZE as u16;
+
+#[derive(Deserialize, Serialize, Default, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Json,
+    #[serde(alias = "yml")]
+    Yaml,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Yaml => "yml",
+        }
+    }
+    fn serialize(self, snapshot: &Snapshot) -> EResult<Vec<u8>> {
+        match self {
+            Format::Json => serde_json::to_vec_pretty(snapshot).map_err(Error::invalid_data),
+            Format::Yaml => serde_yaml::to_string(snapshot)
+                .map(String::into_bytes)
+                .map_err(Error::invalid_data),
+        }
+    }
+    fn deserialize(self, data: &[u8]) -> EResult<Snapshot> {
+        match self {
+            Format::Json => serde_json::from_slice(data).map_err(Error::invalid_data),
+            Format::Yaml => serde_yaml::from_slice(data).map_err(Error::invalid_data),
+        }
+    }
+}
+
+/// Range of non-zero values, bits are stored as 0/1
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct Range<T> {
+    start: u16,
+    values: Vec<T>,
+}
+
+/// Context state, lists non-zero ranges only
+#[derive(Deserialize, Serialize, Default, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Snapshot {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    coils: Vec<Range<u8>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    discretes: Vec<Range<u8>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    inputs: Vec<Range<u16>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    holdings: Vec<Range<u16>>,
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn ranges<T: Copy + Default + PartialEq>(values: &[T]) -> Vec<Range<T>> {
+    let mut result: Vec<Range<T>> = Vec::new();
+    let mut current: Option<Range<T>> = None;
+    for (i, v) in values.iter().enumerate() {
+        if *v == T::default() {
+            if let Some(r) = current.take() {
+                result.push(r);
+            }
+        } else if let Some(ref mut r) = current {
+            r.values.push(*v);
+        } else {
+            current = Some(Range {
+                start: i as u16,
+                values: vec![*v],
+            });
+        }
+    }
+    if let Some(r) = current {
+        result.push(r);
+    }
+    result
+}
+
+impl Snapshot {
+    pub fn from_context(ctx: &ModbusContextFull) -> Self {
+        let mut bits = Vec::with_capacity(FULL_CONTEXT_SIZE);
+        let mut regs = Vec::with_capacity(FULL_CONTEXT_SIZE);
+        ctx.get_coils_bulk(0, CONTEXT_SIZE, &mut bits).unwrap();
+        let coils = ranges(&bits.iter().map(|v| u8::from(*v)).collect::<Vec<u8>>());
+        bits.clear();
+        ctx.get_discretes_bulk(0, CONTEXT_SIZE, &mut bits).unwrap();
+        let discretes = ranges(&bits.iter().map(|v| u8::from(*v)).collect::<Vec<u8>>());
+        ctx.get_inputs_bulk(0, CONTEXT_SIZE, &mut regs).unwrap();
+        let inputs = ranges(&regs);
+        regs.clear();
+        ctx.get_holdings_bulk(0, CONTEXT_SIZE, &mut regs).unwrap();
+        let holdings = ranges(&regs);
+        Self {
+            coils,
+            discretes,
+            inputs,
+            holdings,
+        }
+    }
+    /// Builds a context, out-of-range snapshots are rejected
+    pub fn to_context(&self) -> EResult<ModbusContextFull> {
+        let mut ctx = ModbusContextFull::new();
+        let err = |e| Error::invalid_data(format!("snapshot range error: {}", e));
+        for r in &self.coils {
+            let values: Vec<bool> = r.values.iter().map(|v| *v != 0).collect();
+            ctx.set_coils_bulk(r.start, &values).map_err(err)?;
+        }
+        for r in &self.discretes {
+            let values: Vec<bool> = r.values.iter().map(|v| *v != 0).collect();
+            ctx.set_discretes_bulk(r.start, &values).map_err(err)?;
+        }
+        for r in &self.inputs {
+            ctx.set_inputs_bulk(r.start, &r.values).map_err(err)?;
+        }
+        for r in &self.holdings {
+            ctx.set_holdings_bulk(r.start, &r.values).map_err(err)?;
+        }
+        Ok(ctx)
+    }
+}
+
+/// Writes the file to a temporary one and renames it, so a crash can not leave the target
+/// truncated
+pub async fn write_atomic(path: &Path, data: &[u8]) -> EResult<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let _lock = WRITE_LOCK.lock().await;
+    let mut f = tokio::fs::OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(&tmp)
+        .await?;
+    f.write_all(data).await?;
+    f.sync_all().await?;
+    drop(f);
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+pub async fn save(path: &Path, snapshot: &Snapshot, format: Format) -> EResult<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    write_atomic(path, &format.serialize(snapshot)?).await
+}
+
+/// Returns None if the file does not exist
+pub async fn load(path: &Path, format: Format) -> EResult<Option<Snapshot>> {
+    match tokio::fs::read(path).await {
+        Ok(data) => format.deserialize(&data).map(Some),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[derive(Serialize)]
+pub struct SnapshotInfo {
+    unit: u8,
+    name: String,
+    /// modification time (UNIX timestamp)
+    t: f64,
+}
+
+/// Lists snapshot files in the directory
+pub async fn list(dir: &Path, unit: u8, format: Format) -> EResult<Vec<SnapshotInfo>> {
+    let mut result = Vec::new();
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(result),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|v| v.to_str()) != Some(format.extension()) {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|v| v.to_str()) else {
+            continue;
+        };
+        let t = entry
+            .metadata()
+            .await?
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |v| v.as_secs_f64());
+        result.push(SnapshotInfo {
+            unit,
+            name: name.to_owned(),
+            t,
+        });
+    }
+    result.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(result)
+}
+
+/// Snapshot names are used as file names
+pub fn validate_name(name: &str) -> EResult<()> {
+    if name.is_empty()
+        || name.starts_with('.')
+        || name.contains(|c: char| c == '/' || c == '\\' || c.is_control())
+    {
+        Err(Error::invalid_params(format!(
+            "invalid snapshot name: {}",
+            name
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{validate_name, Format, Snapshot};
+    use rmodbus::server::context::ModbusContextFull;
+
+    #[test]
+    fn snapshot() {
+        let mut ctx = ModbusContextFull::new();
+        ctx.set_coil(5, true).unwrap();
+        ctx.set_holdings_bulk(10, &[1, 2, 0, 3]).unwrap();
+        ctx.set_input(9999, 7).unwrap();
+        let snapshot = Snapshot::from_context(&ctx);
+        assert_eq!(snapshot.holdings.len(), 2);
+        assert_eq!(snapshot.holdings[0].start, 10);
+        assert_eq!(snapshot.holdings[0].values, [1, 2]);
+        assert_eq!(snapshot.inputs[0].start, 9999);
+        for format in [Format::Json, Format::Yaml] {
+            let data = format.serialize(&snapshot).unwrap();
+            let restored = format.deserialize(&data).unwrap();
+            assert_eq!(restored, snapshot);
+            let restored_ctx = restored.to_context().unwrap();
+            assert_eq!(Snapshot::from_context(&restored_ctx), snapshot);
+        }
+        let data = br#"{"inputs": [{"start": 9999, "values": [1, 2]}]}"#;
+        let snapshot = Format::Json.deserialize(data).unwrap();
+        assert!(snapshot.to_context().is_err());
+        assert!(validate_name("before-test").is_ok());
+        assert!(validate_name("../ctx").is_err());
+    }
+}
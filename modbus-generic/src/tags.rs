@@ -0,0 +1,338 @@
+use eva_common::prelude::*;
+pub use eva_sim_modbus::data::Table;
+use eva_sim_modbus::data::{DataType, Encoding, Order};
+use rmodbus::server::context::{ModbusContextFull, FULL_CONTEXT_SIZE};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Returned to clients, writing read-only tags
+const EXCEPTION_ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TagConfig {
+    name: String,
+    table: Table,
+    address: u16,
+    /// register tables only, INT if not set
+    #[serde(default, rename = "type")]
+    tp: Option<DataType>,
+    /// STRING length, in registers
+    #[serde(default)]
+    length: Option<u16>,
+    #[serde(default)]
+    scale: Option<f64>,
+    #[serde(default)]
+    byte_order: Order,
+    #[serde(default)]
+    word_order: Order,
+    /// denies writes by Modbus clients, the tag is still writable with var.set
+    #[serde(default)]
+    read_only: bool,
+    /// initial value
+    #[serde(default)]
+    value: Option<Value>,
+}
+
+#[derive(Clone)]
+struct Tag {
+    table: Table,
+    address: u16,
+    /// None for coils and discretes
+    encoding: Option<Encoding>,
+    read_only: bool,
+}
+
+impl TagConfig {
+    pub fn new(name: &str, table: Table, address: u16, tp: Option<DataType>) -> Self {
+        Self {
+            name: name.to_owned(),
+            table,
+            address,
+            tp,
+            length: None,
+            scale: None,
+            byte_order: <_>::default(),
+            word_order: <_>::default(),
+            read_only: false,
+            value: None,
+        }
+    }
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Tag {
+    fn count(&self) -> u16 {
+        self.encoding.as_ref().map_or(1, Encoding::registers)
+    }
+    /// Tags, mapped to the same range (e.g. imported aliases), do not overlap
+    fn overlaps(&self, other: &Tag) -> bool {
+        let (start, end) = (
+            self.address,
+            u32::from(self.address) + u32::from(self.count()),
+        );
+        let (o_start, o_end) = (
+            other.address,
+            u32::from(other.address) + u32::from(other.count()),
+        );
+        self.table == other.table
+            && (start, end) != (o_start, o_end)
+            && u32::from(start) < o_end
+            && u32::from(o_start) < end
+    }
+    fn get(&self, ctx: &ModbusContextFull) -> EResult<Value> {
+        let Some(ref encoding) = self.encoding else {
+            let val = if self.table == Table::Coil {
+                ctx.get_coil(self.address)
+            } else {
+                ctx.get_discrete(self.address)
+            }
+            .map_err(Error::failed)?;
+            return Ok(Value::Bool(val));
+        };
+        let mut regs = Vec::with_capacity(usize::from(self.count()));
+        if self.table == Table::Holding {
+            ctx.get_holdings_bulk(self.address, self.count(), &mut regs)
+        } else {
+            ctx.get_inputs_bulk(self.address, self.count(), &mut regs)
+        }
+        .map_err(Error::failed)?;
+        encoding.decode(&regs)
+    }
+    fn set(&self, ctx: &mut ModbusContextFull, value: Value) -> EResult<()> {
+        let Some(ref encoding) = self.encoding else {
+            let val: bool = value.try_into()?;
+            return if self.table == Table::Coil {
+                ctx.set_coil(self.address, val)
+            } else {
+                ctx.set_discrete(self.address, val)
+            }
+            .map_err(Error::failed);
+        };
+        let regs = encoding.encode(value)?;
+        if self.table == Table::Holding {
+            ctx.set_holdings_bulk(self.address, &regs)
+        } else {
+            ctx.set_inputs_bulk(self.address, &regs)
+        }
+        .map_err(Error::failed)
+    }
+}
+
+#[derive(Serialize)]
+pub struct TagInfo<'a> {
+    name: &'a str,
+    table: Table,
+    address: u16,
+    count: u16,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    tp: Option<DataType>,
+    read_only: bool,
+    value: Value,
+}
+
+/// Register map, tags by name
+#[derive(Default, Clone)]
+pub struct TagMap {
+    tags: BTreeMap<String, Tag>,
+}
+
+impl TagMap {
+    /// Adds tags, the tags with existing names are redefined, returns initial values of the
+    /// added ones
+    ///
+    /// The map is not modified on errors
+    pub fn extend(&mut self, configs: Vec<TagConfig>) -> EResult<Vec<(String, Value)>> {
+        let mut map = self.clone();
+        let mut initial = Vec::new();
+        let mut added = BTreeSet::new();
+        for config in configs {
+            if !added.insert(config.name.clone()) {
+                return Err(Error::invalid_params(format!(
+                    "tag {} is defined more than once",
+                    config.name
+                )));
+            }
+            if let Some(value) = map.append(config)? {
+                initial.push(value);
+            }
+        }
+        *self = map;
+        Ok(initial)
+    }
+    fn append(&mut self, config: TagConfig) -> EResult<Option<(String, Value)>> {
+        let encoding = if config.table.is_bit() {
+            if config.tp.is_some() || config.length.is_some() || config.scale.is_some() {
+                return Err(Error::invalid_params(format!(
+                    "tag {}: type, length and scale are not supported for bit tables",
+                    config.name
+                )));
+            }
+            None
+        } else {
+            let tp = config.tp.unwrap_or_default();
+            if tp == DataType::String && config.scale.is_some() {
+                return Err(Error::invalid_params(format!(
+                    "tag {}: STRING can not be scaled",
+                    config.name
+                )));
+            }
+            if tp != DataType::String && config.length.is_some() {
+                return Err(Error::invalid_params(format!(
+                    "tag {}: length is supported for STRING only",
+                    config.name
+                )));
+            }
+            if config.length == Some(0) {
+                return Err(Error::invalid_params(format!(
+                    "tag {}: length must be positive",
+                    config.name
+                )));
+            }
+            Some(
+                Encoding::new(tp)
+                    .string_len(config.length.unwrap_or(1))
+                    .byte_order(config.byte_order)
+                    .word_order(config.word_order)
+                    .scale(config.scale),
+            )
+        };
+        let tag = Tag {
+            table: config.table,
+            address: config.address,
+            encoding,
+            read_only: config.read_only,
+        };
+        if usize::from(tag.address) + usize::from(tag.count()) > FULL_CONTEXT_SIZE {
+            return Err(Error::invalid_params(format!(
+                "tag {}: address out of range",
+                config.name
+            )));
+        }
+        self.tags.remove(&config.name);
+        if let Some((name, _)) = self.tags.iter().find(|(_, t)| t.overlaps(&tag)) {
+            return Err(Error::invalid_params(format!(
+                "tag {} overlaps tag {}",
+                config.name, name
+            )));
+        }
+        let initial = config.value.map(|v| (config.name.clone(), v));
+        self.tags.insert(config.name, tag);
+        Ok(initial)
+    }
+    fn get_tag(&self, name: &str) -> EResult<&Tag> {
+        self.tags
+            .get(name)
+            .ok_or_else(|| Error::not_found(format!("tag {} not found", name)))
+    }
+    pub fn get(&self, ctx: &ModbusContextFull, name: &str) -> EResult<Value> {
+        self.get_tag(name)?.get(ctx)
+    }
+    pub fn set(&self, ctx: &mut ModbusContextFull, name: &str, value: Value) -> EResult<()> {
+        self.get_tag(name)?.set(ctx, value)
+    }
+    /// Sets initial tag values
+    pub fn set_values(
+        &self,
+        ctx: &mut ModbusContextFull,
+        values: &[(String, Value)],
+    ) -> EResult<()> {
+        for (name, value) in values {
+            self.set(ctx, name, value.clone())
+                .map_err(|e| Error::invalid_params(format!("tag {}: {}", name, e)))?;
+        }
+        Ok(())
+    }
+    pub fn list<'a>(&'a self, ctx: &ModbusContextFull) -> EResult<Vec<TagInfo<'a>>> {
+        self.tags
+            .iter()
+            .map(|(name, tag)| {
+                Ok(TagInfo {
+                    name,
+                    table: tag.table,
+                    address: tag.address,
+                    count: tag.count(),
+                    tp: tag.encoding.as_ref().map(Encoding::data_type),
+                    read_only: tag.read_only,
+                    value: tag.get(ctx)?,
+                })
+            })
+            .collect()
+    }
+    /// Returns the exception code if a client write function covers read-only tags
+    pub fn write_exception(&self, func: u8, reg: u16, count: u16) -> Option<u8> {
+        let table = match func {
+            5 | 15 => Table::Coil,
+            6 | 16 | 22 => Table::Holding,
+            _ => return None,
+        };
+        let end = u32::from(reg) + u32::from(count);
+        self.tags
+            .values()
+            .any(|tag| {
+                tag.read_only
+                    && tag.table == table
+                    && u32::from(tag.address) < end
+                    && u32::from(tag.address) + u32::from(tag.count()) > u32::from(reg)
+            })
+            .then_some(EXCEPTION_ILLEGAL_DATA_ADDRESS)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Table, TagConfig, TagMap};
+    use eva_common::value::Value;
+    use eva_sim_modbus::data::DataType;
+    use rmodbus::server::context::ModbusContextFull;
+
+    #[test]
+    fn tag_map() {
+        let mut temp = TagConfig::new("temp", Table::Holding, 10, Some(DataType::Realb));
+        temp.read_only = true;
+        temp.value = Some(Value::F64(20.5));
+        let mut map = TagMap::default();
+        let initial = map
+            .extend(vec![temp, TagConfig::new("run", Table::Coil, 1, None)])
+            .unwrap();
+        let mut ctx = ModbusContextFull::new();
+        map.set_values(&mut ctx, &initial).unwrap();
+        assert_eq!(map.get(&ctx, "temp").unwrap(), Value::F32(20.5));
+        map.set(&mut ctx, "run", Value::Bool(true)).unwrap();
+        assert!(ctx.get_coil(1).unwrap());
+        assert_eq!(map.write_exception(16, 8, 3), Some(0x02));
+        assert_eq!(map.write_exception(16, 12, 3), None);
+        assert_eq!(map.write_exception(5, 10, 1), None);
+        assert!(map.get(&ctx, "none").is_err());
+        let bad = TagConfig::new("bad", Table::Input, 9999, Some(DataType::Dint));
+        assert!(map.extend(vec![bad]).is_err());
+    }
+
+    #[test]
+    fn overlaps() {
+        let mut map = TagMap::default();
+        map.extend(vec![
+            TagConfig::new("a", Table::Holding, 0, Some(DataType::Dint)),
+            TagConfig::new("b", Table::Input, 1, None),
+            // alias
+            TagConfig::new("c", Table::Holding, 0, Some(DataType::Real)),
+        ])
+        .unwrap();
+        assert!(map
+            .extend(vec![TagConfig::new("d", Table::Holding, 1, None)])
+            .is_err());
+        assert_eq!(map.tags.len(), 3);
+        // redefined
+        map.extend(vec![TagConfig::new("a", Table::Holding, 10, None)])
+            .unwrap();
+        assert!(map
+            .extend(vec![
+                TagConfig::new("e", Table::Coil, 0, None),
+                TagConfig::new("e", Table::Coil, 1, None)
+            ])
+            .is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use crate::Direction;
+use eva_common::{EResult, Error};
+use eva_sim_pcap::Capture;
+use log::{error, info};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+static PCAP: Lazy<Mutex<Option<Capture<Uuid>>>> = Lazy::new(<_>::default);
+
+/// Client link, used to build synthetic packet headers
+#[derive(Copy, Clone)]
+pub enum Link {
+    Tcp {
+        client: SocketAddr,
+        server: SocketAddr,
+    },
+    Udp {
+        client: SocketAddr,
+        server: SocketAddr,
+    },
+    Serial,
+}
+
+pub fn start(path: &str) -> EResult<()> {
+    let capture = Capture::create(path).map_err(Error::io)?;
+    if let Some(prev) = PCAP.lock().replace(capture) {
+        info!("pcap capture stopped: {}", prev.path());
+    }
+    info!("pcap capture started: {}", path);
+    Ok(())
+}
+
+pub fn stop() -> Option<String> {
+    let path = PCAP.lock().take().map(|c| c.path().to_owned());
+    if let Some(ref p) = path {
+        info!("pcap capture stopped: {}", p);
+    }
+    path
+}
+
+pub fn path() -> Option<String> {
+    PCAP.lock().as_ref().map(|c| c.path().to_owned())
+}
+
+#[inline]
+pub fn is_active() -> bool {
+    PCAP.lock().is_some()
+}
+
+/// Writes a wire-level packet if the capture is active
+pub fn packet(client_id: Uuid, link: Link, direction: Direction, data: &[u8]) {
+    let mut pcap = PCAP.lock();
+    let Some(capture) = pcap.as_mut() else {
+        return;
+    };
+    let direction = match direction {
+        Direction::In => eva_sim_pcap::Direction::Inbound,
+        Direction::Out => eva_sim_pcap::Direction::Outbound,
+    };
+    let result = match link {
+        Link::Tcp { client, server } => {
+            capture.write_tcp(client_id, client, server, direction, data)
+        }
+        Link::Udp { client, server } => capture.write_udp(client, server, direction, data),
+        Link::Serial => capture.write_serial(direction, data),
+    };
+    if let Err(e) = result {
+        error!(
+            "pcap capture {} write error: {}, stopped",
+            capture.path(),
+            e
+        );
+        pcap.take();
+    }
+}
+
+pub fn client_disconnected(client_id: Uuid) {
+    if let Some(capture) = PCAP.lock().as_mut() {
+        capture.remove_flow(&client_id);
+    }
+}
@@ -0,0 +1,241 @@
+use crate::stats::{self, Event, Stats, StatsInfo};
+use crate::Protocol;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+const CLIENT_CLEAN_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Client {
+    tx: async_channel::Sender<Vec<u8>>,
+    listener: Arc<String>,
+    protocol: Protocol,
+    peer: Option<String>,
+    role: Option<String>,
+    connected: f64,
+    idle_timeout: Option<Duration>,
+    last_activity: Instant,
+    stats: Stats,
+}
+
+impl Client {
+    pub fn new(
+        tx: async_channel::Sender<Vec<u8>>,
+        listener: Arc<String>,
+        protocol: Protocol,
+        peer: Option<String>,
+        idle_timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            tx,
+            listener,
+            protocol,
+            peer,
+            role: None,
+            connected: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0.0, |v| v.as_secs_f64()),
+            idle_timeout,
+            last_activity: Instant::now(),
+            stats: Stats::default(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ClientInfo {
+    client_id: Uuid,
+    listener: Arc<String>,
+    protocol: Protocol,
+    peer: Option<String>,
+    role: Option<String>,
+    connected: f64,
+    frames_in: u64,
+    frames_out: u64,
+}
+
+static CLIENTS: Lazy<Mutex<BTreeMap<Uuid, Client>>> = Lazy::new(<_>::default);
+
+/// Registers a client if the listener has got free slots
+///
+/// Listeners are identified by the protocol and the path, so e.g. tcp and udp listeners on the
+/// same host:port have separate limits
+pub fn register(client_id: Uuid, client: Client, max_clients: Option<usize>) -> bool {
+    let mut clients = CLIENTS.lock();
+    if let Some(max) = max_clients {
+        if clients
+            .values()
+            .filter(|c| c.protocol == client.protocol && c.listener == client.listener)
+            .count()
+            >= max
+        {
+            return false;
+        }
+    }
+    clients.insert(client_id, client);
+    true
+}
+
+#[inline]
+pub fn unregister(client_id: Uuid) {
+    CLIENTS.lock().remove(&client_id);
+}
+
+#[inline]
+pub fn is_registered(client_id: Uuid) -> bool {
+    CLIENTS.lock().contains_key(&client_id)
+}
+
+/// Accounts a stats event for the client and its listener
+pub fn account(client_id: Uuid, event: Event) {
+    if let Some(client) = CLIENTS.lock().get_mut(&client_id) {
+        if let Event::FrameIn = event {
+            client.last_activity = Instant::now();
+        }
+        client.stats.apply(&event);
+        stats::account_listener(client.protocol, &client.listener, &event);
+    }
+}
+
+/// Sets the client role, extracted from its certificate
+pub fn set_role(client_id: Uuid, role: String) {
+    if let Some(client) = CLIENTS.lock().get_mut(&client_id) {
+        client.role.replace(role);
+    }
+}
+
+/// Returns the client peer IP address (None for serial port clients) and the client role
+pub fn peer_ip_role(client_id: Uuid) -> (Option<IpAddr>, Option<String>) {
+    CLIENTS.lock().get(&client_id).map_or((None, None), |c| {
+        (
+            c.peer
+                .as_deref()
+                .and_then(|v| v.parse::<SocketAddr>().ok())
+                .map(|v| v.ip()),
+            c.role.clone(),
+        )
+    })
+}
+
+/// Returns true for serial port clients
+pub fn is_serial(client_id: Uuid) -> bool {
+    CLIENTS
+        .lock()
+        .get(&client_id)
+        .is_some_and(|c| c.protocol.is_serial())
+}
+
+/// Returns the client listener path
+pub fn listener(client_id: Uuid) -> Option<Arc<String>> {
+    CLIENTS.lock().get(&client_id).map(|c| c.listener.clone())
+}
+
+/// Returns the client output channel
+pub fn tx(client_id: Uuid) -> Option<async_channel::Sender<Vec<u8>>> {
+    CLIENTS.lock().get(&client_id).map(|c| c.tx.clone())
+}
+
+/// Disconnects the client, serial port clients can not be kicked
+pub fn kick(client_id: Uuid) -> Result<(), &'static str> {
+    let mut clients = CLIENTS.lock();
+    match clients.get(&client_id) {
+        Some(client) if client.protocol.is_serial() => Err("serial port clients can not be kicked"),
+        Some(_) => {
+            // dropping the channel sender stops the client handler
+            clients.remove(&client_id);
+            info!("client {} kicked", client_id);
+            Ok(())
+        }
+        None => Err("client not found"),
+    }
+}
+
+impl ClientInfo {
+    fn new(client_id: Uuid, c: &Client) -> Self {
+        Self {
+            client_id,
+            listener: c.listener.clone(),
+            protocol: c.protocol,
+            peer: c.peer.clone(),
+            role: c.role.clone(),
+            connected: c.connected,
+            frames_in: c.stats.frames_in(),
+            frames_out: c.stats.frames_out(),
+        }
+    }
+}
+
+pub fn get(client_id: Uuid) -> Option<ClientInfo> {
+    CLIENTS
+        .lock()
+        .get(&client_id)
+        .map(|c| ClientInfo::new(client_id, c))
+}
+
+pub fn list() -> Vec<ClientInfo> {
+    CLIENTS
+        .lock()
+        .iter()
+        .map(|(id, c)| ClientInfo::new(*id, c))
+        .collect()
+}
+
+pub fn stats() -> BTreeMap<Uuid, StatsInfo> {
+    CLIENTS
+        .lock()
+        .iter()
+        .map(|(id, c)| (*id, c.stats.info()))
+        .collect()
+}
+
+pub fn reset_stats() {
+    for client in CLIENTS.lock().values_mut() {
+        client.stats = Stats::default();
+    }
+}
+
+/// Disconnects idle clients
+pub async fn cleaner() {
+    let mut int = tokio::time::interval(CLIENT_CLEAN_INTERVAL);
+    loop {
+        int.tick().await;
+        CLIENTS.lock().retain(|id, c| {
+            let active = c.idle_timeout.is_none_or(|t| c.last_activity.elapsed() < t);
+            if !active {
+                warn!("{} client {} idle timeout", c.protocol, id);
+            }
+            active
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{register, unregister, Client};
+    use crate::Protocol;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    #[test]
+    fn max_clients() {
+        let path = Arc::new("127.0.0.1:15021".to_owned());
+        let client = |protocol| {
+            let (tx, _) = async_channel::bounded(1);
+            Client::new(tx, path.clone(), protocol, None, None)
+        };
+        let ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        assert!(register(ids[0], client(Protocol::Tcp), Some(1)));
+        assert!(!register(ids[1], client(Protocol::Tcp), Some(1)));
+        // a udp listener on the same host:port has got own slots
+        assert!(register(ids[2], client(Protocol::Udp), Some(1)));
+        for id in ids {
+            unregister(id);
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use crate::clients;
+use crate::framer::MBAP_HEADER_LEN;
+use crate::Protocol;
+use eva_common::{EResult, Error};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Max reply latency (seconds), replies may be delayed beyond the request timeout to simulate
+/// unresponsive units
+const MAX_LATENCY: f64 = 3600.0;
+
+/// Fault profiles, by name
+static FAULTS: Lazy<Mutex<BTreeMap<String, FaultProfile>>> = Lazy::new(<_>::default);
+
+#[inline]
+fn default_enabled() -> bool {
+    true
+}
+
+/// Fault profile, applied to all units of all listeners unless filtered
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FaultProfile {
+    name: String,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    /// listener path filter
+    #[serde(default)]
+    listener: Option<String>,
+    /// unit id filter
+    #[serde(default)]
+    unit: Option<u8>,
+    /// drop N% of unit replies
+    #[serde(default)]
+    drop: f64,
+    /// reply latency (seconds), random between latency and latency_max if the last is set
+    #[serde(default)]
+    latency: Option<f64>,
+    #[serde(default)]
+    latency_max: Option<f64>,
+    /// corrupt CRC of RTU frames
+    #[serde(default)]
+    corrupt_crc: bool,
+    /// cut N bytes off wire-level reply frames
+    #[serde(default)]
+    truncate: usize,
+    /// reply with a wrong MBAP transaction id
+    #[serde(default)]
+    wrong_tid: bool,
+    #[serde(default)]
+    exceptions: Vec<ExceptionRule>,
+}
+
+/// Replies with the exception code to requests, matching the function and the register range
+/// (inclusive)
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ExceptionRule {
+    #[serde(default)]
+    function: Option<u8>,
+    #[serde(default)]
+    start: Option<u16>,
+    #[serde(default)]
+    end: Option<u16>,
+    code: u8,
+}
+
+impl ExceptionRule {
+    fn matches(&self, frame: &[u8]) -> bool {
+        let Some(func) = frame.get(MBAP_HEADER_LEN + 1) else {
+            return false;
+        };
+        if self.function.is_some_and(|f| f != *func) {
+            return false;
+        }
+        if self.start.is_none() && self.end.is_none() {
+            return true;
+        }
+        let Some((start, end)) = request_range(frame) else {
+            return false;
+        };
+        self.start.unwrap_or(0) <= end && self.end.unwrap_or(u16::MAX) >= start
+    }
+}
+
+impl FaultProfile {
+    fn validate(&self) -> EResult<()> {
+        if !(0.0..=100.0).contains(&self.drop) {
+            return Err(Error::invalid_params("drop must be in range 0..100"));
+        }
+        if self
+            .latency
+            .iter()
+            .chain(self.latency_max.iter())
+            .any(|v| !(0.0..=MAX_LATENCY).contains(v))
+        {
+            return Err(Error::invalid_params(format!(
+                "latency must be in range 0..{}",
+                MAX_LATENCY
+            )));
+        }
+        if let (Some(min), Some(max)) = (self.latency, self.latency_max) {
+            if min > max {
+                return Err(Error::invalid_params("latency is greater than latency_max"));
+            }
+        }
+        for rule in &self.exceptions {
+            if rule.code == 0 {
+                return Err(Error::invalid_params("invalid exception code"));
+            }
+            if let (Some(start), Some(end)) = (rule.start, rule.end) {
+                if start > end {
+                    return Err(Error::invalid_params("invalid exception register range"));
+                }
+            }
+        }
+        Ok(())
+    }
+    fn matches(&self, listener: &str, unit: u8) -> bool {
+        self.enabled
+            && self.listener.as_ref().is_none_or(|l| l == listener)
+            && self.unit.is_none_or(|u| u == unit)
+    }
+    fn delay(&self) -> Option<Duration> {
+        let delay = match (self.latency, self.latency_max) {
+            (None, None) => return None,
+            (Some(v), None) => v,
+            (min, Some(max)) => rand::thread_rng().gen_range(min.unwrap_or_default()..=max),
+        };
+        Some(Duration::from_secs_f64(delay))
+    }
+}
+
+/// Returns the register range (inclusive) of a MBAP request frame
+fn request_range(frame: &[u8]) -> Option<(u16, u16)> {
+    let pdu = frame.get(MBAP_HEADER_LEN + 1..)?;
+    let word = |pos: usize| {
+        pdu.get(pos..pos + 2)
+            .map(|v| u16::from_be_bytes([v[0], v[1]]))
+    };
+    let start = word(1)?;
+    let count = match pdu[0] {
+        0x01..=0x04 | 0x0f | 0x10 | 0x17 => word(3)?,
+        0x05 | 0x06 | 0x16 => 1,
+        _ => return None,
+    };
+    Some((start, start.saturating_add(count.max(1) - 1)))
+}
+
+pub enum Reply {
+    Send,
+    Drop,
+    Delay(Duration),
+}
+
+pub fn set(profile: FaultProfile) -> EResult<()> {
+    profile.validate()?;
+    FAULTS.lock().insert(profile.name.clone(), profile);
+    Ok(())
+}
+
+pub fn set_enabled(name: &str, enabled: bool) -> EResult<()> {
+    FAULTS
+        .lock()
+        .get_mut(name)
+        .map(|p| p.enabled = enabled)
+        .ok_or_else(|| Error::not_found(format!("fault profile {} not found", name)))
+}
+
+/// Removes the profile or all profiles if no name specified
+pub fn clear(name: Option<&str>) -> EResult<()> {
+    let mut faults = FAULTS.lock();
+    if let Some(name) = name {
+        faults
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| Error::not_found(format!("fault profile {} not found", name)))
+    } else {
+        faults.clear();
+        Ok(())
+    }
+}
+
+pub fn list() -> Vec<FaultProfile> {
+    FAULTS.lock().values().cloned().collect()
+}
+
+/// Calls the function for profiles, matching the client listener and the unit
+fn for_matching<F>(client_id: Uuid, unit: u8, mut f: F)
+where
+    F: FnMut(&FaultProfile),
+{
+    let faults = FAULTS.lock();
+    if faults.is_empty() {
+        return;
+    }
+    let Some(listener) = clients::listener(client_id) else {
+        return;
+    };
+    for profile in faults.values().filter(|p| p.matches(&listener, unit)) {
+        f(profile);
+    }
+}
+
+/// Returns an exception code if a request must be answered with an injected exception
+pub fn exception(client_id: Uuid, frame: &[u8]) -> Option<u8> {
+    let mut code = None;
+    for_matching(client_id, frame[MBAP_HEADER_LEN], |profile| {
+        if code.is_none() {
+            code = profile
+                .exceptions
+                .iter()
+                .find(|rule| rule.matches(frame))
+                .map(|rule| rule.code);
+        }
+    });
+    code
+}
+
+/// Applies reply faults to a MBAP frame of a unit service
+pub fn reply(client_id: Uuid, frame: &mut [u8]) -> Reply {
+    let mut drop = false;
+    let mut wrong_tid = false;
+    let mut delay: Option<Duration> = None;
+    for_matching(client_id, frame[MBAP_HEADER_LEN], |profile| {
+        drop |= profile.drop > 0.0 && rand::thread_rng().gen_range(0.0..100.0) < profile.drop;
+        wrong_tid |= profile.wrong_tid;
+        if let Some(d) = profile.delay() {
+            delay = Some(delay.map_or(d, |v| v.max(d)));
+        }
+    });
+    if drop {
+        return Reply::Drop;
+    }
+    if wrong_tid {
+        let tid = u16::from_be_bytes([frame[0], frame[1]]).wrapping_add(1);
+        frame[..2].copy_from_slice(&tid.to_be_bytes());
+    }
+    delay.map_or(Reply::Send, Reply::Delay)
+}
+
+/// Applies wire-level faults to an encoded reply frame
+pub fn wire(client_id: Uuid, unit: u8, protocol: Protocol, data: &mut Vec<u8>) {
+    let mut corrupt_crc = false;
+    let mut truncate = 0;
+    for_matching(client_id, unit, |profile| {
+        corrupt_crc |= profile.corrupt_crc;
+        truncate = truncate.max(profile.truncate);
+    });
+    if corrupt_crc && matches!(protocol, Protocol::Rtu | Protocol::RtuTcp) {
+        if let Some(crc) = data.last_mut() {
+            *crc ^= 0xff;
+        }
+    }
+    data.truncate(data.len().saturating_sub(truncate));
+}
+
+#[cfg(test)]
+mod test {
+    use super::{request_range, ExceptionRule, FaultProfile};
+
+    #[test]
+    fn latency() {
+        let profile = |latency, latency_max| FaultProfile {
+            name: "test".to_owned(),
+            enabled: true,
+            listener: None,
+            unit: None,
+            drop: 0.0,
+            latency,
+            latency_max,
+            corrupt_crc: false,
+            truncate: 0,
+            wrong_tid: false,
+            exceptions: Vec::new(),
+        };
+        assert!(profile(Some(0.5), Some(1.0)).validate().is_ok());
+        assert!(profile(Some(-1.0), None).validate().is_err());
+        assert!(profile(Some(f64::NAN), None).validate().is_err());
+        assert!(profile(None, Some(f64::INFINITY)).validate().is_err());
+        assert!(profile(Some(1e30), None).validate().is_err());
+        assert!(profile(Some(2.0), Some(1.0)).validate().is_err());
+    }
+
+    #[test]
+    fn exception_rule() {
+        // read holding registers 10-19
+        let frame = [0, 1, 0, 0, 0, 6, 1, 3, 0, 10, 0, 10];
+        assert_eq!(request_range(&frame), Some((10, 19)));
+        let rule = |function, start, end| ExceptionRule {
+            function,
+            start,
+            end,
+            code: 2,
+        };
+        assert!(rule(None, None, None).matches(&frame));
+        assert!(rule(Some(3), Some(19), None).matches(&frame));
+        assert!(rule(Some(3), Some(0), Some(10)).matches(&frame));
+        assert!(!rule(Some(4), None, None).matches(&frame));
+        assert!(!rule(Some(3), Some(20), Some(30)).matches(&frame));
+        // write single register 100
+        let frame = [0, 1, 0, 0, 0, 6, 1, 6, 0, 100, 0, 1];
+        assert_eq!(request_range(&frame), Some((100, 100)));
+        assert!(!rule(None, Some(101), None).matches(&frame));
+    }
+}
@@ -0,0 +1,568 @@
+use crc16::{State, MODBUS};
+use std::fmt;
+use std::time::Duration;
+
+/// MBAP header length without the unit id
+pub const MBAP_HEADER_LEN: usize = 6;
+/// Maximum value of the MBAP length field (unit id + PDU)
+const MBAP_MAX_LEN: usize = 254;
+/// Maximum Modbus ASCII frame length: ":", 255 hex-encoded bytes and CR/LF
+const ASCII_MAX_LEN: usize = 1 + 2 * 255 + 2;
+
+pub const EXCEPTION_SERVER_BUSY: u8 = 0x06;
+pub const EXCEPTION_GATEWAY_PATH_UNAVAILABLE: u8 = 0x0a;
+pub const EXCEPTION_GATEWAY_TARGET_FAILED: u8 = 0x0b;
+
+#[derive(Debug)]
+pub enum FrameError {
+    /// CRC/LRC mismatch, the frame is dropped
+    Checksum,
+    /// The frame is broken and dropped
+    Invalid(String),
+    /// The stream can not be resynchronized, the connection must be closed
+    OutOfSync(String),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Checksum => write!(f, "frame checksum error"),
+            FrameError::Invalid(s) => write!(f, "invalid frame: {}", s),
+            FrameError::OutOfSync(s) => write!(f, "stream out of sync: {}", s),
+        }
+    }
+}
+
+/// Converts listener wire frames to MBAP frames, which are published to the bus, and back
+pub enum Framer {
+    Tcp(TcpFramer),
+    Rtu(RtuFramer, u16),
+    RtuTcp(RtuTcpFramer, u16),
+    Ascii(AsciiFramer, u16),
+}
+
+impl Framer {
+    #[inline]
+    pub fn push(&mut self, data: &[u8]) {
+        match self {
+            Framer::Tcp(f) => f.push(data),
+            Framer::Rtu(f, _) => f.push(data),
+            Framer::RtuTcp(f, _) => f.push(data),
+            Framer::Ascii(f, _) => f.push(data),
+        }
+    }
+    /// Returns the next complete MBAP frame, if available
+    ///
+    /// RTU and ASCII frames get sequential transaction ids
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        match self {
+            Framer::Tcp(f) => f.next_frame(),
+            Framer::Rtu(f, tid) => Ok(f
+                .next_frame()
+                .map(|frame| rtu_to_mbap(next_tid(tid), &frame))),
+            Framer::RtuTcp(f, tid) => Ok(f
+                .next_frame()?
+                .map(|frame| rtu_to_mbap(next_tid(tid), &frame))),
+            Framer::Ascii(f, tid) => Ok(f
+                .next_frame()?
+                .map(|frame| rtu_to_mbap(next_tid(tid), &frame))),
+        }
+    }
+    /// Inter-frame silence after which [`Framer::flush`] must be called, if required
+    pub fn silence(&self) -> Option<Duration> {
+        match self {
+            Framer::Rtu(f, _) if !f.is_empty() => Some(f.silence()),
+            _ => None,
+        }
+    }
+    pub fn flush(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        match self {
+            Framer::Rtu(f, tid) => Ok(f.flush()?.map(|frame| rtu_to_mbap(next_tid(tid), &frame))),
+            _ => Ok(None),
+        }
+    }
+    /// Converts a MBAP response frame to the wire format
+    pub fn encode(&self, frame: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Framer::Tcp(_) => Some(frame.to_vec()),
+            Framer::Rtu(..) | Framer::RtuTcp(..) => mbap_to_rtu(frame),
+            Framer::Ascii(..) => mbap_to_ascii(frame),
+        }
+    }
+}
+
+#[inline]
+fn next_tid(tid: &mut u16) -> u16 {
+    *tid = tid.wrapping_add(1);
+    *tid
+}
+
+/// Slices a Modbus/TCP byte stream into frames by the MBAP length field
+#[derive(Default)]
+pub struct TcpFramer {
+    buf: Vec<u8>,
+}
+
+impl TcpFramer {
+    #[inline]
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+    /// Returns the next complete frame, if available
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        if self.buf.len() < MBAP_HEADER_LEN {
+            return Ok(None);
+        }
+        let len = usize::from(u16::from_be_bytes([self.buf[4], self.buf[5]]));
+        if !(2..=MBAP_MAX_LEN).contains(&len) {
+            return Err(FrameError::OutOfSync(format!(
+                "invalid MBAP length: {}",
+                len
+            )));
+        }
+        if self.buf.len() < MBAP_HEADER_LEN + len {
+            return Ok(None);
+        }
+        let rest = self.buf.split_off(MBAP_HEADER_LEN + len);
+        Ok(Some(std::mem::replace(&mut self.buf, rest)))
+    }
+}
+
+/// Slices a Modbus RTU byte stream into request frames
+///
+/// Frames are cut by their function-code-aware expected length as soon as the CRC matches,
+/// frames of unknown length (or broken ones) are cut by the inter-frame silence (t3.5)
+pub struct RtuFramer {
+    buf: Vec<u8>,
+    silence: Duration,
+}
+
+impl RtuFramer {
+    pub fn new(baud_rate: u32) -> Self {
+        // the standard recommends the fixed 1.75ms gap for baud rates above 19200
+        let silence = if baud_rate > 19_200 || baud_rate == 0 {
+            Duration::from_micros(1_750)
+        } else {
+            // 3.5 characters, 11 bits each
+            Duration::from_micros(38_500_000 / u64::from(baud_rate))
+        };
+        Self {
+            buf: Vec::new(),
+            silence,
+        }
+    }
+    /// t3.5 inter-frame silence
+    #[inline]
+    pub fn silence(&self) -> Duration {
+        self.silence
+    }
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+    #[inline]
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+    /// Returns the next complete frame with CRC stripped, if its length is known from the
+    /// function code
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        let len = rtu_request_len(&self.buf)?;
+        if self.buf.len() < len || !crc_valid(&self.buf[..len]) {
+            return None;
+        }
+        let rest = self.buf.split_off(len);
+        let mut frame = std::mem::replace(&mut self.buf, rest);
+        frame.truncate(len - 2);
+        Some(frame)
+    }
+    /// Must be called after the inter-frame silence, returns the buffered frame with CRC
+    /// stripped
+    pub fn flush(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let mut frame = std::mem::take(&mut self.buf);
+        if frame.len() < 4 || !crc_valid(&frame) {
+            return Err(FrameError::Checksum);
+        }
+        frame.truncate(frame.len() - 2);
+        Ok(Some(frame))
+    }
+}
+
+/// Slices a Modbus RTU over TCP byte stream into request frames
+///
+/// There is no inter-frame timing on TCP, frames are cut by their function-code-aware
+/// expected length only, so requests of unknown functions can not be framed
+#[derive(Default)]
+pub struct RtuTcpFramer {
+    buf: Vec<u8>,
+}
+
+impl RtuTcpFramer {
+    #[inline]
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+    /// Returns the next complete frame with CRC stripped, if available
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        let Some(func) = self.buf.get(1).copied() else {
+            return Ok(None);
+        };
+        if !rtu_request_known(func) {
+            return Err(FrameError::OutOfSync(format!(
+                "unknown function: 0x{:02x}",
+                func
+            )));
+        }
+        let Some(len) = rtu_request_len(&self.buf) else {
+            return Ok(None);
+        };
+        if self.buf.len() < len {
+            return Ok(None);
+        }
+        let rest = self.buf.split_off(len);
+        let mut frame = std::mem::replace(&mut self.buf, rest);
+        if !crc_valid(&frame) {
+            return Err(FrameError::Checksum);
+        }
+        frame.truncate(len - 2);
+        Ok(Some(frame))
+    }
+}
+
+/// Slices a Modbus ASCII byte stream into frames, started with ":" and terminated by CR/LF
+#[derive(Default)]
+pub struct AsciiFramer {
+    buf: Vec<u8>,
+}
+
+impl AsciiFramer {
+    #[inline]
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+    /// Returns the next complete frame with LRC stripped, if available
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        // drop garbage before the frame start
+        let Some(start) = self.buf.iter().position(|v| *v == b':') else {
+            self.buf.clear();
+            return Ok(None);
+        };
+        self.buf.drain(..start);
+        let Some(end) = self
+            .buf
+            .iter()
+            .take(ASCII_MAX_LEN)
+            .position(|v| *v == b'\n')
+        else {
+            if self.buf.len() >= ASCII_MAX_LEN {
+                // drop the unterminated frame and resync on the next frame start
+                let next = self.buf[1..]
+                    .iter()
+                    .position(|v| *v == b':')
+                    .map_or(self.buf.len(), |pos| pos + 1);
+                self.buf.drain(..next);
+                return Err(FrameError::Invalid("frame too long".to_owned()));
+            }
+            return Ok(None);
+        };
+        let rest = self.buf.split_off(end + 1);
+        let data = std::mem::replace(&mut self.buf, rest);
+        let hex_data = data[1..end]
+            .strip_suffix(b"\r")
+            .ok_or_else(|| FrameError::Invalid("no CR before LF".to_owned()))?;
+        let mut frame = hex::decode(hex_data).map_err(|e| FrameError::Invalid(e.to_string()))?;
+        if frame.len() < 3 {
+            return Err(FrameError::Invalid("frame too short".to_owned()));
+        }
+        let lrc = frame.pop().unwrap();
+        if lrc != calc_lrc(&frame) {
+            return Err(FrameError::Checksum);
+        }
+        Ok(Some(frame))
+    }
+}
+
+fn calc_lrc(data: &[u8]) -> u8 {
+    data.iter()
+        .fold(0_u8, |acc, v| acc.wrapping_add(*v))
+        .wrapping_neg()
+}
+
+pub fn crc_valid(frame: &[u8]) -> bool {
+    let len = frame.len();
+    len > 2 && State::<MODBUS>::calculate(&frame[..len - 2]).to_le_bytes() == frame[len - 2..]
+}
+
+/// Returns true if the length of RTU requests of the function can be calculated
+fn rtu_request_known(func: u8) -> bool {
+    matches!(
+        func,
+        0x01..=0x08 | 0x0b | 0x0c | 0x0f..=0x11 | 0x14..=0x18 | 0x2b
+    )
+}
+
+/// Expected length (incl. unit id and CRC) of a RTU request, if can be calculated
+fn rtu_request_len(buf: &[u8]) -> Option<usize> {
+    let byte_at = |pos: usize| buf.get(pos).map(|v| usize::from(*v));
+    match *buf.get(1)? {
+        0x01..=0x06 | 0x08 => Some(8),
+        0x07 | 0x0b | 0x0c | 0x11 => Some(4),
+        0x0f | 0x10 => byte_at(6).map(|c| 9 + c),
+        0x14 | 0x15 => byte_at(2).map(|c| 5 + c),
+        0x16 => Some(10),
+        0x17 => byte_at(10).map(|c| 13 + c),
+        0x18 => Some(6),
+        0x2b => Some(7),
+        _ => None,
+    }
+}
+
+/// Expected length (incl. unit id and CRC) of a RTU response, if can be calculated
+pub fn rtu_response_len(buf: &[u8]) -> Option<usize> {
+    let byte_at = |pos: usize| buf.get(pos).map(|v| usize::from(*v));
+    let func = *buf.get(1)?;
+    if func & 0x80 != 0 {
+        return Some(5);
+    }
+    match func {
+        0x01..=0x04 | 0x0c | 0x11 | 0x14 | 0x15 | 0x17 => byte_at(2).map(|c| 5 + c),
+        0x05 | 0x06 | 0x08 | 0x0b | 0x0f | 0x10 => Some(8),
+        0x07 => Some(5),
+        0x16 => Some(10),
+        0x18 => Some(6 + (byte_at(2)? << 8 | byte_at(3)?)),
+        _ => None,
+    }
+}
+
+/// Converts a RTU frame (without CRC) to a MBAP frame
+pub fn rtu_to_mbap(tid: u16, frame: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(frame.len() + MBAP_HEADER_LEN);
+    result.extend(tid.to_be_bytes());
+    result.extend([0, 0]);
+    #[allow(clippy::cast_possible_truncation)]
+    result.extend((frame.len() as u16).to_be_bytes());
+    result.extend(frame);
+    result
+}
+
+/// Converts a MBAP frame to a RTU frame with CRC
+pub fn mbap_to_rtu(frame: &[u8]) -> Option<Vec<u8>> {
+    if frame.len() <= MBAP_HEADER_LEN + 1 {
+        return None;
+    }
+    let mut result = frame[MBAP_HEADER_LEN..].to_vec();
+    let crc: u16 = State::<MODBUS>::calculate(&result);
+    result.extend(crc.to_le_bytes());
+    Some(result)
+}
+
+/// Converts a MBAP frame to a Modbus ASCII frame
+pub fn mbap_to_ascii(frame: &[u8]) -> Option<Vec<u8>> {
+    if frame.len() <= MBAP_HEADER_LEN + 1 {
+        return None;
+    }
+    let data = &frame[MBAP_HEADER_LEN..];
+    let mut result = Vec::with_capacity(data.len() * 2 + 5);
+    result.push(b':');
+    result.extend(hex::encode_upper(data).as_bytes());
+    result.extend(hex::encode_upper([calc_lrc(data)]).as_bytes());
+    result.extend(b"\r\n");
+    Some(result)
+}
+
+/// Builds a MBAP exception response for the MBAP request frame
+pub fn exception_frame(frame: &[u8], code: u8) -> Vec<u8> {
+    let mut result = Vec::with_capacity(MBAP_HEADER_LEN + 3);
+    result.extend(&frame[..4]);
+    result.extend(3_u16.to_be_bytes());
+    result.push(frame[MBAP_HEADER_LEN]);
+    result.push(frame[MBAP_HEADER_LEN + 1] | 0x80);
+    result.push(code);
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        exception_frame, mbap_to_ascii, mbap_to_rtu, rtu_response_len, AsciiFramer, FrameError,
+        Framer, RtuFramer, RtuTcpFramer, TcpFramer,
+    };
+
+    const REQ1: [u8; 12] = [0, 1, 0, 0, 0, 6, 1, 3, 0, 0, 0, 2];
+    const REQ2: [u8; 12] = [0, 2, 0, 0, 0, 6, 1, 4, 0, 10, 0, 1];
+
+    #[test]
+    fn tcp_coalesced() {
+        let mut framer = TcpFramer::default();
+        let mut data = REQ1.to_vec();
+        data.extend(REQ2);
+        framer.push(&data);
+        assert_eq!(framer.next_frame().unwrap().unwrap(), REQ1);
+        assert_eq!(framer.next_frame().unwrap().unwrap(), REQ2);
+        assert!(framer.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn tcp_fragmented() {
+        let mut framer = TcpFramer::default();
+        framer.push(&REQ1[..4]);
+        assert!(framer.next_frame().unwrap().is_none());
+        framer.push(&REQ1[4..9]);
+        assert!(framer.next_frame().unwrap().is_none());
+        framer.push(&REQ1[9..]);
+        framer.push(&REQ2[..1]);
+        assert_eq!(framer.next_frame().unwrap().unwrap(), REQ1);
+        assert!(framer.next_frame().unwrap().is_none());
+        framer.push(&REQ2[1..]);
+        assert_eq!(framer.next_frame().unwrap().unwrap(), REQ2);
+    }
+
+    #[test]
+    fn tcp_invalid_length() {
+        let mut framer = TcpFramer::default();
+        framer.push(&[0, 1, 0, 0, 0xff, 0xff, 1, 3]);
+        assert!(framer.next_frame().is_err());
+    }
+
+    const RTU_REQ1: [u8; 8] = [0x01, 0x03, 0x00, 0x00, 0x00, 0x02, 0xc4, 0x0b];
+    const RTU_REQ2: [u8; 13] = [
+        0x01, 0x10, 0x00, 0x01, 0x00, 0x02, 0x04, 0x00, 0x0a, 0x01, 0x02, 0x92, 0x30,
+    ];
+
+    #[test]
+    fn rtu_split() {
+        let mut framer = RtuFramer::new(9600);
+        framer.push(&RTU_REQ2[..3]);
+        assert!(framer.next_frame().is_none());
+        framer.push(&RTU_REQ2[3..8]);
+        assert!(framer.next_frame().is_none());
+        framer.push(&RTU_REQ2[8..]);
+        assert_eq!(framer.next_frame().unwrap(), RTU_REQ2[..11]);
+        assert!(framer.is_empty());
+    }
+
+    #[test]
+    fn rtu_back_to_back() {
+        let mut framer = RtuFramer::new(9600);
+        let mut data = RTU_REQ1.to_vec();
+        data.extend(RTU_REQ2);
+        framer.push(&data);
+        assert_eq!(framer.next_frame().unwrap(), RTU_REQ1[..6]);
+        assert_eq!(framer.next_frame().unwrap(), RTU_REQ2[..11]);
+        assert!(framer.next_frame().is_none());
+    }
+
+    #[test]
+    fn rtu_silence() {
+        assert_eq!(RtuFramer::new(9600).silence().as_micros(), 4010);
+        assert_eq!(RtuFramer::new(115_200).silence().as_micros(), 1750);
+        let mut framer = RtuFramer::new(9600);
+        // unknown function, cut by silence only
+        let mut frame = vec![0x01, 0x64, 0x01];
+        frame.extend(mbap_to_rtu(&[0, 0, 0, 0, 0, 3, 0x01, 0x64, 0x01]).unwrap()[3..].iter());
+        framer.push(&frame);
+        assert!(framer.next_frame().is_none());
+        assert_eq!(framer.flush().unwrap().unwrap(), [0x01, 0x64, 0x01]);
+        framer.push(&RTU_REQ1[..7]);
+        assert!(framer.next_frame().is_none());
+        assert!(framer.flush().is_err());
+        assert!(framer.is_empty());
+    }
+
+    #[test]
+    fn rtu_tcp() {
+        let mut framer = RtuTcpFramer::default();
+        framer.push(&RTU_REQ1[..7]);
+        assert!(framer.next_frame().unwrap().is_none());
+        framer.push(&RTU_REQ1[7..]);
+        framer.push(&RTU_REQ2);
+        assert_eq!(framer.next_frame().unwrap().unwrap(), RTU_REQ1[..6]);
+        assert_eq!(framer.next_frame().unwrap().unwrap(), RTU_REQ2[..11]);
+        assert!(framer.next_frame().unwrap().is_none());
+        // broken frames are dropped by the expected length
+        let mut data = RTU_REQ1.to_vec();
+        data[7] ^= 0xff;
+        data.extend(RTU_REQ1);
+        framer.push(&data);
+        assert!(matches!(framer.next_frame(), Err(FrameError::Checksum)));
+        assert_eq!(framer.next_frame().unwrap().unwrap(), RTU_REQ1[..6]);
+        framer.push(&[0x01, 0x64, 0x01]);
+        assert!(matches!(framer.next_frame(), Err(FrameError::OutOfSync(_))));
+    }
+
+    #[test]
+    fn ascii() {
+        let mut framer = AsciiFramer::default();
+        framer.push(b"\x00:010300000002FA\r");
+        assert!(framer.next_frame().unwrap().is_none());
+        framer.push(b"\n:010300000002FB\r\n:01030000");
+        assert_eq!(
+            framer.next_frame().unwrap().unwrap(),
+            [0x01, 0x03, 0x00, 0x00, 0x00, 0x02]
+        );
+        assert!(matches!(framer.next_frame(), Err(FrameError::Checksum)));
+        assert!(framer.next_frame().unwrap().is_none());
+        assert_eq!(
+            mbap_to_ascii(&[0, 1, 0, 0, 0, 6, 0x01, 0x03, 0x00, 0x00, 0x00, 0x02]).unwrap(),
+            b":010300000002FA\r\n"
+        );
+    }
+
+    #[test]
+    fn ascii_oversized() {
+        let mut framer = AsciiFramer::default();
+        framer.push(b":");
+        framer.push(&[b'0'; 600]);
+        assert!(matches!(framer.next_frame(), Err(FrameError::Invalid(_))));
+        assert!(framer.buf.is_empty());
+        framer.push(&[b'0'; 100]);
+        framer.push(b":010300000002FA\r\n");
+        assert_eq!(
+            framer.next_frame().unwrap().unwrap(),
+            [0x01, 0x03, 0x00, 0x00, 0x00, 0x02]
+        );
+    }
+
+    #[test]
+    fn framer_tid() {
+        let mut framer = Framer::Rtu(RtuFramer::new(9600), 0);
+        let mut data = RTU_REQ1.to_vec();
+        data.extend(RTU_REQ1);
+        framer.push(&data);
+        assert_eq!(
+            framer.next_frame().unwrap().unwrap(),
+            [0, 1, 0, 0, 0, 6, 0x01, 0x03, 0x00, 0x00, 0x00, 0x02]
+        );
+        assert_eq!(
+            framer.next_frame().unwrap().unwrap(),
+            [0, 2, 0, 0, 0, 6, 0x01, 0x03, 0x00, 0x00, 0x00, 0x02]
+        );
+        assert_eq!(
+            framer
+                .encode(&[0, 2, 0, 0, 0, 3, 0x01, 0x83, 0x02])
+                .unwrap(),
+            [0x01, 0x83, 0x02, 0xc0, 0xf1]
+        );
+    }
+
+    #[test]
+    fn rtu_response() {
+        // read holding registers, 2 registers
+        assert_eq!(rtu_response_len(&[0x01, 0x03]), None);
+        assert_eq!(rtu_response_len(&[0x01, 0x03, 0x04]), Some(9));
+        assert_eq!(rtu_response_len(&[0x01, 0x06]), Some(8));
+        assert_eq!(rtu_response_len(&[0x01, 0x83]), Some(5));
+        assert_eq!(rtu_response_len(&[0x01, 0x18, 0x00, 0x06]), Some(12));
+    }
+
+    #[test]
+    fn exception() {
+        assert_eq!(
+            exception_frame(&REQ1, 0x0a),
+            [0, 1, 0, 0, 0, 3, 1, 0x83, 0x0a]
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use crate::upstream::{Upstream, UpstreamProtocol};
+use eva_common::tools::de_opt_float_as_duration;
+use eva_common::{EResult, Error};
+use log::info;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Forwarded units, by local unit id
+static ROUTES: OnceCell<BTreeMap<u8, Route>> = OnceCell::new();
+
+/// Forwards frames for the units to a remote slave
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GatewayConfig {
+    path: String,
+    protocol: UpstreamProtocol,
+    #[serde(default, deserialize_with = "de_opt_float_as_duration")]
+    timeout: Option<Duration>,
+    units: Vec<UnitMap>,
+}
+
+impl GatewayConfig {
+    /// Returns the serial port path for RTU upstreams
+    pub fn serial_path(&self) -> Option<&str> {
+        (self.protocol == UpstreamProtocol::Rtu).then_some(self.path.as_str())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UnitMap {
+    unit: u8,
+    /// unit id on the remote slave, the same as local if not set
+    #[serde(default)]
+    remote_unit: Option<u8>,
+}
+
+#[derive(Clone)]
+pub struct Route {
+    upstream: Arc<Upstream>,
+    remote_unit: u8,
+}
+
+impl Route {
+    #[inline]
+    pub fn upstream(&self) -> &Upstream {
+        &self.upstream
+    }
+    #[inline]
+    pub fn remote_unit(&self) -> u8 {
+        self.remote_unit
+    }
+}
+
+#[derive(Serialize)]
+pub struct RouteInfo {
+    unit: u8,
+    remote_unit: u8,
+    path: String,
+}
+
+pub fn init(configs: Vec<GatewayConfig>, default_timeout: Duration) -> EResult<()> {
+    let mut routes = BTreeMap::new();
+    for config in configs {
+        let upstream = Arc::new(Upstream::new(
+            &config.path,
+            config.protocol,
+            config.timeout.unwrap_or(default_timeout),
+        )?);
+        for map in config.units {
+            if map.unit == 0 {
+                return Err(Error::invalid_params("broadcast unit can not be forwarded"));
+            }
+            let route = Route {
+                upstream: upstream.clone(),
+                remote_unit: map.remote_unit.unwrap_or(map.unit),
+            };
+            if routes.insert(map.unit, route).is_some() {
+                return Err(Error::invalid_params(format!(
+                    "unit {} is forwarded more than once",
+                    map.unit
+                )));
+            }
+            info!(
+                "unit {} forwarded to {} unit {}",
+                map.unit,
+                upstream.path(),
+                map.remote_unit.unwrap_or(map.unit)
+            );
+        }
+    }
+    ROUTES
+        .set(routes)
+        .map_err(|_| Error::core("Unable to set ROUTES"))
+}
+
+/// Returns the route if the unit is forwarded to a remote slave
+#[inline]
+pub fn route(unit: u8) -> Option<Route> {
+    ROUTES.get().and_then(|r| r.get(&unit)).cloned()
+}
+
+pub fn list() -> Vec<RouteInfo> {
+    ROUTES
+        .get()
+        .map(|routes| {
+            routes
+                .iter()
+                .map(|(unit, route)| RouteInfo {
+                    unit: *unit,
+                    remote_unit: route.remote_unit,
+                    path: route.upstream.path().to_owned(),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
@@ -0,0 +1,176 @@
+use crate::stats::ListenerKey;
+use crate::Protocol;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Serial port reopen backoff
+const RECONNECT_DELAY_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    /// The listener is bound / the serial port is open
+    Online,
+    /// The serial port is absent or failed, waiting to be reopened
+    Offline,
+}
+
+struct Listener {
+    state: State,
+    since: f64,
+    error: Option<String>,
+    /// set once the listener has been online, so the first open is not counted as reconnect
+    has_been_online: bool,
+    reconnects: u64,
+}
+
+#[derive(Serialize)]
+pub struct ListenerInfo {
+    path: Arc<String>,
+    protocol: Protocol,
+    state: State,
+    since: f64,
+    error: Option<String>,
+    reconnects: u64,
+}
+
+static LISTENERS: Lazy<Mutex<BTreeMap<ListenerKey, Listener>>> = Lazy::new(<_>::default);
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |v| v.as_secs_f64())
+}
+
+pub fn register(path: Arc<String>, protocol: Protocol, state: State) {
+    LISTENERS.lock().insert(
+        (protocol, path),
+        Listener {
+            state,
+            since: now(),
+            error: None,
+            has_been_online: state == State::Online,
+            reconnects: 0,
+        },
+    );
+}
+
+/// Marks the listener online, increments the reconnect counter if it has gone offline after
+/// being online
+pub fn set_online(protocol: Protocol, path: &Arc<String>) {
+    if let Some(listener) = LISTENERS.lock().get_mut(&(protocol, path.clone())) {
+        listener.set_online();
+    }
+}
+
+/// Marks the listener offline, keeps the previous state change time if already offline
+pub fn set_offline(protocol: Protocol, path: &Arc<String>, error: String) {
+    if let Some(listener) = LISTENERS.lock().get_mut(&(protocol, path.clone())) {
+        listener.set_offline(error);
+    }
+}
+
+impl Listener {
+    fn set_online(&mut self) {
+        if self.state == State::Offline && self.has_been_online {
+            self.reconnects += 1;
+        }
+        if self.state != State::Online {
+            self.since = now();
+        }
+        self.state = State::Online;
+        self.has_been_online = true;
+        self.error = None;
+    }
+    fn set_offline(&mut self, error: String) {
+        if self.state != State::Offline {
+            self.since = now();
+        }
+        self.state = State::Offline;
+        self.error = Some(error);
+    }
+}
+
+pub fn list() -> Vec<ListenerInfo> {
+    LISTENERS
+        .lock()
+        .iter()
+        .map(|((protocol, path), l)| ListenerInfo {
+            path: path.clone(),
+            protocol: *protocol,
+            state: l.state,
+            since: l.since,
+            error: l.error.clone(),
+            reconnects: l.reconnects,
+        })
+        .collect()
+}
+
+/// Exponential reopen delay
+pub struct Backoff {
+    delay: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            delay: RECONNECT_DELAY_MIN,
+        }
+    }
+}
+
+impl Backoff {
+    /// Returns the current delay and doubles the next one
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(RECONNECT_DELAY_MAX);
+        delay
+    }
+    pub fn reset(&mut self) {
+        self.delay = RECONNECT_DELAY_MIN;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Backoff, Listener, State};
+    use std::time::Duration;
+
+    #[test]
+    fn reconnects() {
+        let mut listener = Listener {
+            state: State::Offline,
+            since: 0.0,
+            error: None,
+            has_been_online: false,
+            reconnects: 0,
+        };
+        // the port is absent on startup
+        listener.set_offline("not found".to_owned());
+        listener.set_online();
+        assert_eq!(listener.reconnects, 0);
+        assert!(listener.error.is_none());
+        listener.set_offline("unplugged".to_owned());
+        listener.set_offline("not found".to_owned());
+        listener.set_online();
+        assert_eq!(listener.reconnects, 1);
+        assert!(listener.error.is_none());
+    }
+
+    #[test]
+    fn backoff() {
+        let mut backoff = Backoff::default();
+        let delays: Vec<Duration> = (0..7).map(|_| backoff.next_delay()).collect();
+        assert_eq!(
+            delays,
+            [1, 2, 4, 8, 16, 30, 30].map(Duration::from_secs).to_vec()
+        );
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+}
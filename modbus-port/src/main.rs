@@ -1,27 +1,57 @@
 use bmart_derive::EnumStr;
 use busrt::QoS;
-use crc16::{State, MODBUS};
+use capture::Link;
+use clients::Client;
 use eva_common::prelude::*;
+use eva_common::tools::de_opt_float_as_duration;
 use eva_sdk::prelude::*;
-use once_cell::sync::{Lazy, OnceCell};
-use parking_lot::Mutex;
-use serde::Deserialize;
-use std::collections::BTreeMap;
+use framer::{
+    exception_frame, FrameError, Framer, RtuFramer, TcpFramer, EXCEPTION_GATEWAY_PATH_UNAVAILABLE,
+    EXCEPTION_GATEWAY_TARGET_FAILED, EXCEPTION_SERVER_BUSY, MBAP_HEADER_LEN,
+};
+use listeners::Backoff;
+use once_cell::sync::OnceCell;
+use openssl::ssl::{Ssl, SslAcceptor};
+use serde::{Deserialize, Serialize};
+use stats::{Event, ListenerStatsInfo, StatsInfo};
+use std::collections::{BTreeMap, BTreeSet};
+use std::pin::Pin;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
-use tokio_serial::{DataBits, Parity, SerialPortBuilderExt, StopBits};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio_openssl::SslStream;
+use tokio_serial::{DataBits, Parity, SerialPortBuilder, SerialPortBuilderExt, StopBits};
 use uuid::Uuid;
 
+mod capture;
+mod clients;
+mod faults;
+mod framer;
+mod gateway;
+mod listeners;
+mod master;
+mod pending;
+mod stats;
+mod tls;
+mod units;
+mod upstream;
+
 const AUTHOR: &str = "Bohemia Automation";
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const DESCRIPTION: &str = "SIM Virtual Modbus port";
 
+/// Frames for unit 0 are delivered to all unit services, which never reply
+const BROADCAST_UNIT: u8 = 0;
+
+const PENDING_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// UDP peers do not disconnect, so they are always expired
+const UDP_DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
 static RPC: OnceCell<Arc<RpcClient>> = OnceCell::new();
 static BUS_TOPIC_IN: OnceCell<String> = OnceCell::new();
 static BUS_TOPIC_OUT: OnceCell<String> = OnceCell::new();
-static CLIENTS: Lazy<Mutex<BTreeMap<Uuid, async_channel::Sender<Vec<u8>>>>> =
-    Lazy::new(<_>::default);
 
 #[cfg(not(feature = "std-alloc"))]
 #[global_allocator]
@@ -33,14 +63,215 @@ struct Handlers {
     info: ServiceInfo,
 }
 
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UnitPayload {
+    unit: u8,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClientPayload {
+    client_id: Uuid,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FaultPayload {
+    #[serde(default)]
+    name: Option<String>,
+}
+
 #[async_trait::async_trait]
 impl RpcHandlers for Handlers {
     // Handle RPC call
     async fn handle_call(&self, event: RpcEvent) -> RpcResult {
         svc_rpc_need_ready!();
         let method = event.parse_method()?;
-        #[allow(clippy::match_single_binding)]
+        let payload = event.payload();
         match method {
+            "unit.ping" => {
+                if payload.is_empty() {
+                    Err(RpcError::params(None))
+                } else {
+                    let p: UnitPayload = unpack(payload)?;
+                    if p.unit == BROADCAST_UNIT {
+                        return Err(
+                            Error::invalid_params("broadcast unit can not be registered").into(),
+                        );
+                    }
+                    units::ping(p.unit, event.sender());
+                    Ok(None)
+                }
+            }
+            "unit.unregister" => {
+                if payload.is_empty() {
+                    Err(RpcError::params(None))
+                } else {
+                    let p: UnitPayload = unpack(payload)?;
+                    units::unregister(p.unit, event.sender());
+                    Ok(None)
+                }
+            }
+            "unit.list" => {
+                if payload.is_empty() {
+                    Ok(Some(pack(&units::list())?))
+                } else {
+                    Err(RpcError::params(None))
+                }
+            }
+            "listener.list" => {
+                if payload.is_empty() {
+                    Ok(Some(pack(&listeners::list())?))
+                } else {
+                    Err(RpcError::params(None))
+                }
+            }
+            "stats.get" => {
+                #[derive(Serialize)]
+                struct StatsPayload {
+                    listeners: Vec<ListenerStatsInfo>,
+                    clients: BTreeMap<Uuid, StatsInfo>,
+                }
+                if payload.is_empty() {
+                    Ok(Some(pack(&StatsPayload {
+                        listeners: stats::listeners(),
+                        clients: clients::stats(),
+                    })?))
+                } else {
+                    Err(RpcError::params(None))
+                }
+            }
+            "stats.reset" => {
+                if payload.is_empty() {
+                    stats::reset_listeners();
+                    clients::reset_stats();
+                    Ok(None)
+                } else {
+                    Err(RpcError::params(None))
+                }
+            }
+            "pcap.start" => {
+                #[derive(Deserialize)]
+                #[serde(deny_unknown_fields)]
+                struct PcapPayload {
+                    path: String,
+                }
+                if payload.is_empty() {
+                    Err(RpcError::params(None))
+                } else {
+                    let p: PcapPayload = unpack(payload)?;
+                    capture::start(&p.path)?;
+                    Ok(None)
+                }
+            }
+            "pcap.stop" => {
+                if payload.is_empty() {
+                    capture::stop();
+                    Ok(None)
+                } else {
+                    Err(RpcError::params(None))
+                }
+            }
+            "pcap.status" => {
+                #[derive(Serialize)]
+                struct PcapStatus {
+                    active: bool,
+                    path: Option<String>,
+                }
+                if payload.is_empty() {
+                    let path = capture::path();
+                    Ok(Some(pack(&PcapStatus {
+                        active: path.is_some(),
+                        path,
+                    })?))
+                } else {
+                    Err(RpcError::params(None))
+                }
+            }
+            "fault.set" => {
+                if payload.is_empty() {
+                    Err(RpcError::params(None))
+                } else {
+                    faults::set(unpack(payload)?)?;
+                    Ok(None)
+                }
+            }
+            "fault.enable" | "fault.disable" => {
+                if payload.is_empty() {
+                    Err(RpcError::params(None))
+                } else {
+                    let p: FaultPayload = unpack(payload)?;
+                    faults::set_enabled(
+                        p.name.as_deref().ok_or_else(|| RpcError::params(None))?,
+                        method == "fault.enable",
+                    )?;
+                    Ok(None)
+                }
+            }
+            "fault.clear" => {
+                let name = if payload.is_empty() {
+                    None
+                } else {
+                    unpack::<FaultPayload>(payload)?.name
+                };
+                faults::clear(name.as_deref())?;
+                Ok(None)
+            }
+            "fault.list" => {
+                if payload.is_empty() {
+                    Ok(Some(pack(&faults::list())?))
+                } else {
+                    Err(RpcError::params(None))
+                }
+            }
+            "gateway.list" => {
+                if payload.is_empty() {
+                    Ok(Some(pack(&gateway::list())?))
+                } else {
+                    Err(RpcError::params(None))
+                }
+            }
+            "master.get" => {
+                #[derive(Deserialize)]
+                #[serde(deny_unknown_fields)]
+                struct MasterPayload {
+                    #[serde(default)]
+                    name: Option<String>,
+                }
+                let name = if payload.is_empty() {
+                    None
+                } else {
+                    unpack::<MasterPayload>(payload)?.name
+                };
+                Ok(Some(pack(&master::results(name.as_deref())?)?))
+            }
+            "client.list" => {
+                if payload.is_empty() {
+                    Ok(Some(pack(&clients::list())?))
+                } else {
+                    Err(RpcError::params(None))
+                }
+            }
+            "client.get" => {
+                if payload.is_empty() {
+                    Err(RpcError::params(None))
+                } else {
+                    let p: ClientPayload = unpack(payload)?;
+                    let info = clients::get(p.client_id)
+                        .ok_or_else(|| Error::not_found("client not found"))?;
+                    Ok(Some(pack(&info)?))
+                }
+            }
+            "client.kick" => {
+                if payload.is_empty() {
+                    Err(RpcError::params(None))
+                } else {
+                    let p: ClientPayload = unpack(payload)?;
+                    clients::kick(p.client_id).map_err(Error::failed)?;
+                    Ok(None)
+                }
+            }
             _ => svc_handle_default_rpc(method, &self.info),
         }
     }
@@ -50,9 +281,12 @@ impl RpcHandlers for Handlers {
             if let Some(cid) = topic.strip_prefix(BUS_TOPIC_OUT.get().unwrap()) {
                 match cid.parse::<Uuid>() {
                     Ok(client_id) => {
-                        let tx_o = CLIENTS.lock().get(&client_id).map(Clone::clone);
-                        if let Some(tx) = tx_o {
-                            tx.send(frame.payload().to_vec()).await.log_ef();
+                        let data = frame.payload();
+                        if let Some(latency) = pending::complete(client_id, data) {
+                            clients::account(client_id, Event::Latency(latency));
+                            deliver_reply(client_id, data.to_vec()).await;
+                        } else {
+                            warn!("client {} late or unexpected reply dropped", client_id);
                         }
                     }
                     Err(e) => {
@@ -70,25 +304,96 @@ struct Config {
     #[serde(default)]
     listen: Vec<ListenConfig>,
     #[serde(default)]
+    on_timeout: TimeoutAction,
+    #[serde(default)]
+    pcap: Option<String>,
+    #[serde(default)]
+    faults: Vec<faults::FaultProfile>,
+    #[serde(default)]
+    master: Vec<master::MasterConfig>,
+    #[serde(default)]
+    gateway: Vec<gateway::GatewayConfig>,
+    #[serde(default)]
     verbose: bool,
 }
 
+impl Config {
+    /// A serial port can be opened once only, so it can not be shared by listeners, gateway
+    /// upstreams and master poll targets
+    fn check_serial_ports(&self) -> EResult<()> {
+        let paths = self
+            .listen
+            .iter()
+            .filter(|l| l.protocol.is_serial())
+            .map(|l| l.path.as_str())
+            .chain(self.gateway.iter().filter_map(|g| g.serial_path()))
+            .chain(self.master.iter().filter_map(|m| m.serial_path()));
+        let mut devices = BTreeSet::new();
+        for path in paths {
+            let device = path.split(':').next().unwrap_or_default();
+            if !devices.insert(device) {
+                return Err(Error::invalid_params(format!(
+                    "serial port {} is used more than once",
+                    device
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What to do if a unit service does not reply in time
+#[derive(Deserialize, Default, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum TimeoutAction {
+    /// Reply with the gateway target failed to respond exception
+    #[default]
+    Exception,
+    /// Do not reply
+    Drop,
+}
+
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 struct ListenConfig {
     path: String,
     protocol: Protocol,
+    #[serde(default)]
+    max_clients: Option<usize>,
+    #[serde(default, deserialize_with = "de_opt_float_as_duration")]
+    idle_timeout: Option<Duration>,
+    #[serde(default)]
+    tls: Option<tls::TlsConfig>,
 }
 
-#[derive(Deserialize, Copy, Clone, EnumStr)]
+#[derive(Deserialize, Serialize, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, EnumStr)]
 #[serde(rename_all = "lowercase")]
 #[enumstr(rename_all = "lowercase")]
 enum Protocol {
     Tcp,
     Rtu,
+    RtuTcp,
+    Ascii,
+    AsciiTcp,
+    Udp,
+    Tls,
 }
 
-#[derive(EnumStr)]
+impl Protocol {
+    pub fn is_serial(self) -> bool {
+        matches!(self, Protocol::Rtu | Protocol::Ascii)
+    }
+    fn framer(self, baud_rate: u32) -> Framer {
+        match self {
+            Protocol::Tcp | Protocol::Udp | Protocol::Tls => Framer::Tcp(<_>::default()),
+            Protocol::Rtu => Framer::Rtu(RtuFramer::new(baud_rate), 0),
+            Protocol::RtuTcp => Framer::RtuTcp(<_>::default(), 0),
+            Protocol::Ascii | Protocol::AsciiTcp => Framer::Ascii(<_>::default(), 0),
+        }
+    }
+}
+
+#[derive(EnumStr, Copy, Clone)]
 #[enumstr(rename_all = "lowercase")]
 enum Direction {
     In,
@@ -106,65 +411,326 @@ fn log_packet(protocol: Protocol, direction: Direction, client_id: Uuid, data: &
     );
 }
 
-async fn launch_tcp_server(listen: &str, verbose: bool) -> EResult<()> {
-    let listener = TcpListener::bind(&listen).await?;
-    info!("tcp port ready {}", listen);
-    tokio::spawn(async move {
-        loop {
-            match listener.accept().await {
-                Ok((mut stream, addr)) => {
-                    tokio::spawn(async move {
-                        let client_id = Uuid::new_v4();
+async fn send_to_client(client_id: Uuid, frame: Vec<u8>) {
+    if let Some(tx) = clients::tx(client_id) {
+        clients::account(client_id, Event::FrameOut);
+        if frame
+            .get(MBAP_HEADER_LEN + 1)
+            .is_some_and(|f| f & 0x80 != 0)
+        {
+            clients::account(client_id, Event::Exception);
+        }
+        tx.send(frame).await.log_ef();
+    }
+}
+
+/// Sends a unit reply to the client, applying fault injection
+async fn deliver_reply(client_id: Uuid, mut frame: Vec<u8>) {
+    match faults::reply(client_id, &mut frame) {
+        faults::Reply::Send => send_to_client(client_id, frame).await,
+        faults::Reply::Drop => {
+            warn!("client {} reply dropped by fault injection", client_id);
+        }
+        faults::Reply::Delay(delay) => {
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                send_to_client(client_id, frame).await;
+            });
+        }
+    }
+}
+
+/// Forwards the frame to a remote slave, replies with a gateway exception on errors
+async fn forward_frame(client_id: Uuid, route: gateway::Route, mut frame: Vec<u8>) {
+    let unit = frame[MBAP_HEADER_LEN];
+    frame[MBAP_HEADER_LEN] = route.remote_unit();
+    let started = Instant::now();
+    match route.upstream().transact(&frame).await {
+        Ok(mut reply) => {
+            clients::account(client_id, Event::Latency(started.elapsed()));
+            reply[MBAP_HEADER_LEN] = unit;
+            deliver_reply(client_id, reply).await;
+        }
+        Err(e) => {
+            error!(
+                "client {} unit {} forward error ({}): {}",
+                client_id,
+                unit,
+                route.upstream().path(),
+                e
+            );
+            frame[MBAP_HEADER_LEN] = unit;
+            send_gateway_exception(client_id, &frame, EXCEPTION_GATEWAY_TARGET_FAILED).await;
+        }
+    }
+}
+
+/// Replies with a gateway exception, serial port clients get no reply, as the unit may be
+/// served by another device on the bus
+async fn send_gateway_exception(client_id: Uuid, frame: &[u8], code: u8) {
+    if !clients::is_serial(client_id) {
+        send_to_client(client_id, exception_frame(frame, code)).await;
+    }
+}
+
+/// Publishes the frame to the unit topic, the client peer IP ("-" for serial port clients)
+/// and the client role (Modbus/TCP Security) are passed as the last topic parts
+async fn publish_frame(client_id: Uuid, unit: u8, frame: Vec<u8>) {
+    let (ip, role) = clients::peer_ip_role(client_id);
+    let ip = ip.map_or_else(|| "-".to_owned(), |v| v.to_string());
+    let topic = if let Some(role) = role {
+        format!(
+            "{}{}/{}/{}/{}",
+            BUS_TOPIC_IN.get().unwrap(),
+            unit,
+            client_id,
+            ip,
+            role
+        )
+    } else {
+        format!(
+            "{}{}/{}/{}",
+            BUS_TOPIC_IN.get().unwrap(),
+            unit,
+            client_id,
+            ip
+        )
+    };
+    RPC.get()
+        .unwrap()
+        .client()
+        .lock()
+        .await
+        .publish(&topic, frame.into(), QoS::Processed)
+        .await
+        .log_ef();
+}
+
+/// Forwards the frame to the service the unit is registered by or replies with a gateway
+/// exception if there is no such (TCP-family listeners only)
+///
+/// Broadcast frames are published to all unit services and are never replied
+async fn route_frame(client_id: Uuid, frame: Vec<u8>) {
+    clients::account(client_id, Event::FrameIn);
+    if frame[MBAP_HEADER_LEN] == BROADCAST_UNIT {
+        publish_frame(client_id, BROADCAST_UNIT, frame).await;
+        return;
+    }
+    if let Some(code) = faults::exception(client_id, &frame) {
+        send_to_client(client_id, exception_frame(&frame, code)).await;
+        return;
+    }
+    let unit = frame[MBAP_HEADER_LEN];
+    if let Some(route) = gateway::route(unit) {
+        tokio::spawn(forward_frame(client_id, route, frame));
+    } else if units::is_registered(unit) {
+        if pending::register(client_id, &frame) {
+            publish_frame(client_id, unit, frame).await;
+        } else {
+            warn!(
+                "client {} unit {} duplicate transaction id",
+                client_id, unit
+            );
+            send_to_client(client_id, exception_frame(&frame, EXCEPTION_SERVER_BUSY)).await;
+        }
+    } else {
+        send_gateway_exception(client_id, &frame, EXCEPTION_GATEWAY_PATH_UNAVAILABLE).await;
+    }
+}
+
+async fn pending_watcher(timeout: Duration, action: TimeoutAction) {
+    let mut int = tokio::time::interval(PENDING_CHECK_INTERVAL);
+    loop {
+        int.tick().await;
+        for (client_id, head) in pending::take_expired(timeout) {
+            warn!(
+                "client {} unit {} request timed out",
+                client_id, head[MBAP_HEADER_LEN]
+            );
+            if action == TimeoutAction::Exception {
+                send_gateway_exception(client_id, &head, EXCEPTION_GATEWAY_TARGET_FAILED).await;
+            }
+        }
+    }
+}
+
+/// Captures a complete inbound frame in the wire format
+fn capture_frame(client_id: Uuid, link: Link, framer: &Framer, frame: &[u8]) {
+    if !capture::is_active() {
+        return;
+    }
+    if let Some(data) = framer.encode(frame) {
+        capture::packet(client_id, link, Direction::In, &data);
+    }
+}
+
+async fn handle_client<S>(
+    mut stream: S,
+    client_id: Uuid,
+    rx: async_channel::Receiver<Vec<u8>>,
+    protocol: Protocol,
+    link: Link,
+    mut framer: Framer,
+    verbose: bool,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; 1024];
+    'outer: loop {
+        let silence = framer.silence();
+        tokio::select! {
+            ev = rx.recv() => {
+                if let Ok(data) = ev {
+                    let unit = data.get(MBAP_HEADER_LEN).copied().unwrap_or_default();
+                    let Some(mut data) = framer.encode(&data) else {
+                        error!("invalid bus/out packet");
+                        continue;
+                    };
+                    faults::wire(client_id, unit, protocol, &mut data);
+                    if verbose {
+                        log_packet(protocol, Direction::Out, client_id, &data);
+                    }
+                    capture::packet(client_id, link, Direction::Out, &data);
+                    if let Err(e) = stream.write_all(&data).await {
+                        error!("{} client {} write error: {}", protocol, client_id, e);
+                        break;
+                    }
+                    clients::account(client_id, Event::BytesOut(data.len()));
+                } else {
+                    break;
+                }
+            }
+            read = stream.read(&mut buf) => {
+                match read {
+                    Ok(0) => {
                         if verbose {
-                            info!("tcp client connected ({}): {}", addr, client_id);
+                            info!("{} client disconnected: {}", protocol, client_id);
                         }
-                        let (tx, rx) = async_channel::bounded(1024);
-                        CLIENTS.lock().insert(client_id, tx);
-                        loop {
-                            let mut buf = vec![0; 256];
-                            tokio::select! {
-                                ev = rx.recv() => {
-                                    if let Ok(data) = ev {
-                                        if verbose {
-                                            log_packet(
-                                                Protocol::Tcp, Direction::Out, client_id, &data);
-                                        }
-                                        if let Err(e) = stream.write_all(&data).await {
-                                            error!("tcp client {} write error: {}", client_id, e);
-                                            break;
-                                        }
-                                    } else {
-                                        break;
-                                    }
-                                }
-                                read = stream.read(&mut buf) => {
-                                    let len = read.unwrap_or(0);
-                                    if len < 6 {
-                                        if verbose {
-                                            info!("tcp client disconnected: {}", client_id);
-                                        }
-                                        break;
-                                    }
-                                    buf.truncate(len);
-                                    if verbose {
-                                        log_packet(
-                                            Protocol::Tcp, Direction::In, client_id, &buf);
-                                    }
-                                    let topic = format!("{}{}",
-                                        BUS_TOPIC_IN.get().unwrap(), client_id);
-                                    RPC.get()
-                                        .unwrap()
-                                        .client()
-                                        .lock()
-                                        .await
-                                        .publish(&topic, buf.into(), QoS::Processed)
-                                        .await
-                                        .log_ef();
-                                        }
+                        break;
+                    }
+                    Ok(len) => {
+                        clients::account(client_id, Event::BytesIn(len));
+                        if verbose {
+                            log_packet(protocol, Direction::In, client_id, &buf[..len]);
+                        }
+                        framer.push(&buf[..len]);
+                    }
+                    Err(e) => {
+                        error!("{} client {} read error: {}", protocol, client_id, e);
+                        break;
+                    }
+                }
+                loop {
+                    match framer.next_frame() {
+                        Ok(Some(frame)) => {
+                            capture_frame(client_id, link, &framer, &frame);
+                            route_frame(client_id, frame).await;
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            error!("{} client {} {}", protocol, client_id, e);
+                            clients::account(client_id, Event::from(&e));
+                            if let FrameError::OutOfSync(_) = e {
+                                break 'outer;
                             }
                         }
-                        CLIENTS.lock().remove(&client_id);
-                    });
+                    }
+                }
+            }
+            () = tokio::time::sleep(silence.unwrap_or_default()), if silence.is_some() => {
+                match framer.flush() {
+                    Ok(Some(frame)) => {
+                        capture_frame(client_id, link, &framer, &frame);
+                        route_frame(client_id, frame).await;
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        error!("{} client {} {}", protocol, client_id, e);
+                        clients::account(client_id, Event::from(&e));
+                    }
+                }
+            }
+        }
+    }
+    clients::unregister(client_id);
+    capture::client_disconnected(client_id);
+}
+
+async fn launch_tcp_server(listen: ListenConfig, verbose: bool) -> EResult<()> {
+    let protocol = listen.protocol;
+    let acceptor = match (protocol, &listen.tls) {
+        (Protocol::Tls, Some(config)) => Some(Arc::new(tls::acceptor(config)?)),
+        (Protocol::Tls, None) => {
+            return Err(Error::invalid_params(
+                "tls config is required for tls listeners",
+            ))
+        }
+        (_, Some(_)) => {
+            return Err(Error::invalid_params(
+                "tls config is supported for tls listeners only",
+            ))
+        }
+        (_, None) => None,
+    };
+    let listener = TcpListener::bind(&listen.path).await?;
+    let path = Arc::new(listen.path);
+    stats::register_listener(protocol, path.clone());
+    listeners::register(path.clone(), protocol, listeners::State::Online);
+    info!("{} port ready {}", protocol, path);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    let client_id = Uuid::new_v4();
+                    let (tx, rx) = async_channel::bounded(1024);
+                    let client = Client::new(
+                        tx,
+                        path.clone(),
+                        protocol,
+                        Some(addr.to_string()),
+                        listen.idle_timeout,
+                    );
+                    if !clients::register(client_id, client, listen.max_clients) {
+                        warn!(
+                            "{} client rejected ({}): {} max clients reached",
+                            protocol, addr, path
+                        );
+                        continue;
+                    }
+                    if verbose {
+                        info!("{} client connected ({}): {}", protocol, addr, client_id);
+                    }
+                    let link = match stream.local_addr() {
+                        Ok(server) => Link::Tcp {
+                            client: addr,
+                            server,
+                        },
+                        Err(e) => {
+                            error!("{} client {} error: {}", protocol, client_id, e);
+                            clients::unregister(client_id);
+                            continue;
+                        }
+                    };
+                    if let Some(ref acceptor) = acceptor {
+                        tokio::spawn(handle_tls_client(
+                            stream,
+                            acceptor.clone(),
+                            client_id,
+                            rx,
+                            link,
+                            verbose,
+                        ));
+                    } else {
+                        tokio::spawn(handle_client(
+                            stream,
+                            client_id,
+                            rx,
+                            protocol,
+                            link,
+                            protocol.framer(0),
+                            verbose,
+                        ));
+                    }
                 }
                 Err(e) => {
                     error!("listener error: {}", e);
@@ -175,9 +741,164 @@ async fn launch_tcp_server(listen: &str, verbose: bool) -> EResult<()> {
     Ok(())
 }
 
-#[allow(clippy::too_many_lines)]
-async fn launch_rtu_server(listen: String, verbose: bool) -> EResult<()> {
-    let mut sp = listen.split(':');
+/// Performs TLS handshake, extracts the client role and handles the client
+async fn handle_tls_client(
+    stream: TcpStream,
+    acceptor: Arc<SslAcceptor>,
+    client_id: Uuid,
+    rx: async_channel::Receiver<Vec<u8>>,
+    link: Link,
+    verbose: bool,
+) {
+    let handshake = async {
+        let ssl = Ssl::new(acceptor.context()).map_err(Error::io)?;
+        let mut stream = SslStream::new(ssl, stream).map_err(Error::io)?;
+        tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, Pin::new(&mut stream).accept())
+            .await?
+            .map_err(Error::io)?;
+        Ok::<_, Error>(stream)
+    };
+    let stream = match handshake.await {
+        Ok(v) => v,
+        Err(e) => {
+            error!("tls client {} handshake error: {}", client_id, e);
+            clients::unregister(client_id);
+            return;
+        }
+    };
+    let role = match stream
+        .ssl()
+        .peer_certificate()
+        .and_then(|cert| cert.to_der().ok())
+        .map(|der| tls::cert_role(&der))
+        .transpose()
+    {
+        Ok(v) => v.flatten(),
+        Err(e) => {
+            error!("tls client {} certificate rejected: {}", client_id, e);
+            clients::unregister(client_id);
+            return;
+        }
+    };
+    if let Some(role) = role {
+        if role.contains(['/', '+', '#']) {
+            warn!(
+                "tls client {} role ignored, invalid value: {}",
+                client_id, role
+            );
+        } else {
+            if verbose {
+                info!("tls client {} role: {}", client_id, role);
+            }
+            clients::set_role(client_id, role);
+        }
+    }
+    handle_client(
+        stream,
+        client_id,
+        rx,
+        Protocol::Tls,
+        link,
+        Protocol::Tls.framer(0),
+        verbose,
+    )
+    .await;
+}
+
+async fn launch_udp_server(listen: ListenConfig, verbose: bool) -> EResult<()> {
+    if listen.tls.is_some() {
+        return Err(Error::invalid_params(
+            "tls config is supported for tls listeners only",
+        ));
+    }
+    let socket = Arc::new(UdpSocket::bind(&listen.path).await?);
+    let local_addr = socket.local_addr()?;
+    let path = Arc::new(listen.path);
+    stats::register_listener(Protocol::Udp, path.clone());
+    listeners::register(path.clone(), Protocol::Udp, listeners::State::Online);
+    info!("udp port ready {}", path);
+    tokio::spawn(async move {
+        let mut buf = vec![0; 1024];
+        loop {
+            let (len, addr) = match socket.recv_from(&mut buf).await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("udp listener error: {}", e);
+                    continue;
+                }
+            };
+            let client_id =
+                Uuid::new_v5(&Uuid::NAMESPACE_URL, format!("udp://{}", addr).as_bytes());
+            let link = Link::Udp {
+                client: addr,
+                server: local_addr,
+            };
+            if verbose {
+                log_packet(Protocol::Udp, Direction::In, client_id, &buf[..len]);
+            }
+            capture::packet(client_id, link, Direction::In, &buf[..len]);
+            if !clients::is_registered(client_id) {
+                let (tx, rx) = async_channel::bounded::<Vec<u8>>(1024);
+                let client = Client::new(
+                    tx,
+                    path.clone(),
+                    Protocol::Udp,
+                    Some(addr.to_string()),
+                    Some(listen.idle_timeout.unwrap_or(UDP_DEFAULT_IDLE_TIMEOUT)),
+                );
+                if !clients::register(client_id, client, listen.max_clients) {
+                    warn!(
+                        "udp client rejected ({}): {} max clients reached",
+                        addr, path
+                    );
+                    continue;
+                }
+                if verbose {
+                    info!("udp client registered ({}): {}", addr, client_id);
+                }
+                let socket = socket.clone();
+                tokio::spawn(async move {
+                    while let Ok(mut data) = rx.recv().await {
+                        let unit = data.get(MBAP_HEADER_LEN).copied().unwrap_or_default();
+                        faults::wire(client_id, unit, Protocol::Udp, &mut data);
+                        if verbose {
+                            log_packet(Protocol::Udp, Direction::Out, client_id, &data);
+                        }
+                        capture::packet(client_id, link, Direction::Out, &data);
+                        if let Err(e) = socket.send_to(&data, addr).await {
+                            error!("udp client {} write error: {}", client_id, e);
+                        } else {
+                            clients::account(client_id, Event::BytesOut(data.len()));
+                        }
+                    }
+                });
+            }
+            clients::account(client_id, Event::BytesIn(len));
+            let mut framer = TcpFramer::default();
+            framer.push(&buf[..len]);
+            let frame = match framer.next_frame() {
+                Ok(Some(v)) => v,
+                Ok(None) => {
+                    error!("udp client {} incomplete datagram", client_id);
+                    clients::account(client_id, Event::ParseError);
+                    continue;
+                }
+                Err(e) => {
+                    error!("udp client {} {}", client_id, e);
+                    clients::account(client_id, Event::from(&e));
+                    continue;
+                }
+            };
+            route_frame(client_id, frame).await;
+        }
+    });
+    Ok(())
+}
+
+/// Parses a serial port path (port:baud_rate:data_bits:parity:stop_bits), returns the port
+/// builder and the baud rate
+fn serial_port(path: &str) -> EResult<(SerialPortBuilder, u32)> {
+    let mut sp = path.split(':');
     let port_path = sp.next().unwrap();
     let baud_rate: u32 = sp
         .next()
@@ -225,80 +946,73 @@ async fn launch_rtu_server(listen: String, verbose: bool) -> EResult<()> {
             )))
         }
     };
-    let mut stream = tokio_serial::new(port_path, baud_rate)
+    let builder = tokio_serial::new(port_path, baud_rate)
         .data_bits(data_bits)
         .parity(parity)
-        .stop_bits(stop_bits)
-        .open_native_async()
-        .map_err(Error::io)?;
+        .stop_bits(stop_bits);
+    Ok((builder, baud_rate))
+}
+
+async fn launch_serial_server(listen: ListenConfig, verbose: bool) -> EResult<()> {
+    if listen.max_clients.is_some() || listen.idle_timeout.is_some() {
+        return Err(Error::invalid_params(
+            "max_clients and idle_timeout are not supported for serial ports",
+        ));
+    }
+    if listen.tls.is_some() {
+        return Err(Error::invalid_params(
+            "tls config is supported for tls listeners only",
+        ));
+    }
+    let protocol = listen.protocol;
+    let path = Arc::new(listen.path);
+    let (builder, baud_rate) = serial_port(&path)?;
     let client_id = Uuid::new_v4();
     if verbose {
-        info!("rtu client id: {}", client_id);
+        info!("{} client id: {}", protocol, client_id);
     }
-    let (tx, rx) = async_channel::bounded(1024);
-    CLIENTS.lock().insert(client_id, tx);
-    info!("rtu port ready {}", listen);
+    stats::register_listener(protocol, path.clone());
+    listeners::register(path.clone(), protocol, listeners::State::Offline);
+    // the port is (re)opened in background, so a missing device does not stop the service
     tokio::spawn(async move {
+        let mut backoff = Backoff::default();
         loop {
-            let mut buf = vec![0; 256];
-            tokio::select! {
-                ev = rx.recv() => {
-                    if let Ok(mut data) = ev {
-                        if data.len() < 7 {
-                            error!("invalid bus/rt packet");
-                            continue;
-                        }
-                        data = data.split_off(6);
-                        let crc: u16 = State::<MODBUS>::calculate(&data);
-                        data.extend(crc.to_le_bytes());
-                        if verbose {
-                            log_packet(
-                                Protocol::Tcp, Direction::Out, client_id, &data);
-                        }
-                        if let Err(e) = stream.write_all(&data).await {
-                            error!("rtu client {} write error: {}", client_id, e);
-                            continue;
-                        }
-                    } else {
-                        break;
-                    }
+            let stream = match builder.clone().open_native_async() {
+                Ok(v) => v,
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    error!(
+                        "{} port {} open error: {}, retrying in {:?}",
+                        protocol, path, e, delay
+                    );
+                    listeners::set_offline(protocol, &path, e.to_string());
+                    tokio::time::sleep(delay).await;
+                    continue;
                 }
-                read = stream.read(&mut buf) => {
-                    let len = read.unwrap_or(0);
-                    if len < 3 {
-                        continue;
-                    }
-                    buf.truncate(len);
-                    if verbose {
-                        log_packet(
-                            Protocol::Rtu, Direction::In, client_id, &buf);
-                    }
-                    let crc: u16 = State::<MODBUS>::calculate(&buf[..len-2]);
-                    if crc.to_le_bytes() != buf[len-2..] {
-                        error!("rtu client {} frame checksum error", client_id);
-                        continue;
-                    }
-                    buf.truncate(len-2);
-                    let mut req = Vec::with_capacity(len + 4);
-                    req.extend(&[0, 0, 0, 0]);
-                    #[allow(clippy::cast_possible_truncation)]
-                    req.extend((len as u16).to_be_bytes());
-                    req.extend(&buf);
-                    let topic = format!("{}{}",
-                        BUS_TOPIC_IN.get().unwrap(), client_id);
-                    RPC.get()
-                        .unwrap()
-                        .client()
-                        .lock()
-                        .await
-                        .publish(&topic, req.into(), QoS::Processed)
-                        .await
-                        .log_ef();
-                        }
-            }
+            };
+            backoff.reset();
+            let (tx, rx) = async_channel::bounded(1024);
+            clients::register(
+                client_id,
+                Client::new(tx, path.clone(), protocol, None, None),
+                None,
+            );
+            listeners::set_online(protocol, &path);
+            info!("{} port ready {}", protocol, path);
+            handle_client(
+                stream,
+                client_id,
+                rx,
+                protocol,
+                Link::Serial,
+                protocol.framer(baud_rate),
+                verbose,
+            )
+            .await;
+            warn!("{} port closed {}", protocol, path);
+            listeners::set_offline(protocol, &path, "port closed".to_owned());
+            tokio::time::sleep(backoff.next_delay()).await;
         }
-        CLIENTS.lock().remove(&client_id);
-        info!("rtu port closed {}", listen);
     });
     Ok(())
 }
@@ -310,7 +1024,38 @@ async fn main(mut initial: Initial) -> EResult<()> {
             .take_config()
             .ok_or_else(|| Error::invalid_data("config not specified"))?,
     )?;
-    let info = ServiceInfo::new(AUTHOR, VERSION, DESCRIPTION);
+    config.check_serial_ports()?;
+    let mut info = ServiceInfo::new(AUTHOR, VERSION, DESCRIPTION);
+    info.add_method(ServiceMethod::new("unit.list"));
+    info.add_method(ServiceMethod::new("listener.list"));
+    info.add_method(ServiceMethod::new("client.list"));
+    info.add_method(ServiceMethod::new("client.get").required("client_id"));
+    info.add_method(ServiceMethod::new("client.kick").required("client_id"));
+    info.add_method(ServiceMethod::new("stats.get"));
+    info.add_method(ServiceMethod::new("stats.reset"));
+    info.add_method(ServiceMethod::new("pcap.start").required("path"));
+    info.add_method(ServiceMethod::new("pcap.stop"));
+    info.add_method(ServiceMethod::new("pcap.status"));
+    info.add_method(
+        ServiceMethod::new("fault.set")
+            .required("name")
+            .optional("enabled")
+            .optional("listener")
+            .optional("unit")
+            .optional("drop")
+            .optional("latency")
+            .optional("latency_max")
+            .optional("corrupt_crc")
+            .optional("truncate")
+            .optional("wrong_tid")
+            .optional("exceptions"),
+    );
+    info.add_method(ServiceMethod::new("fault.enable").required("name"));
+    info.add_method(ServiceMethod::new("fault.disable").required("name"));
+    info.add_method(ServiceMethod::new("fault.clear").optional("name"));
+    info.add_method(ServiceMethod::new("fault.list"));
+    info.add_method(ServiceMethod::new("master.get").optional("name"));
+    info.add_method(ServiceMethod::new("gateway.list"));
     let rpc = initial.init_rpc(Handlers { info }).await?;
     initial.drop_privileges()?;
     let client = rpc.client().clone();
@@ -331,13 +1076,29 @@ async fn main(mut initial: Initial) -> EResult<()> {
         .map_err(|_| Error::core("Unable to set BUS_TOPIC_OUT"))?;
     svc_init_logs(&initial, client.clone())?;
     svc_start_signal_handlers();
+    tokio::spawn(units::cleaner());
+    tokio::spawn(clients::cleaner());
+    for profile in config.faults {
+        faults::set(profile)?;
+    }
+    if let Some(ref path) = config.pcap {
+        capture::start(path)?;
+    }
+    tokio::spawn(pending_watcher(initial.timeout(), config.on_timeout));
+    gateway::init(config.gateway, initial.timeout())?;
+    for master in config.master {
+        master::launch(master, initial.id(), initial.timeout())?;
+    }
     for listen in config.listen {
         match listen.protocol {
-            Protocol::Tcp => {
-                launch_tcp_server(&listen.path, config.verbose).await?;
+            Protocol::Udp => {
+                launch_udp_server(listen, config.verbose).await?;
+            }
+            p if p.is_serial() => {
+                launch_serial_server(listen, config.verbose).await?;
             }
-            Protocol::Rtu => {
-                launch_rtu_server(listen.path, config.verbose).await?;
+            _ => {
+                launch_tcp_server(listen, config.verbose).await?;
             }
         }
     }
@@ -0,0 +1,204 @@
+use crate::upstream::{Upstream, UpstreamProtocol};
+use crate::RPC;
+use busrt::rpc::Rpc;
+use busrt::QoS;
+use eva_common::payload::pack;
+use eva_common::tools::{de_float_as_duration, de_opt_float_as_duration};
+use eva_common::{EResult, Error};
+use log::{error, info};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rmodbus::client::ModbusRequest;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Latest poll results, by master name, None if not polled yet
+static RESULTS: Lazy<Mutex<BTreeMap<String, Vec<Option<PollResult>>>>> = Lazy::new(<_>::default);
+
+/// Polls a remote slave by the schedule
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MasterConfig {
+    name: String,
+    path: String,
+    protocol: UpstreamProtocol,
+    #[serde(default, deserialize_with = "de_opt_float_as_duration")]
+    timeout: Option<Duration>,
+    poll: Vec<PollConfig>,
+}
+
+impl MasterConfig {
+    /// Returns the serial port path for RTU slaves
+    pub fn serial_path(&self) -> Option<&str> {
+        (self.protocol == UpstreamProtocol::Rtu).then_some(self.path.as_str())
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct PollConfig {
+    unit: u8,
+    /// read function: 1 (coils), 2 (discretes), 3 (holdings) or 4 (inputs)
+    function: u8,
+    address: u16,
+    count: u16,
+    #[serde(deserialize_with = "de_float_as_duration")]
+    interval: Duration,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(untagged)]
+enum Values {
+    Bits(Vec<bool>),
+    Registers(Vec<u16>),
+}
+
+#[derive(Serialize, Clone)]
+pub struct PollResult {
+    unit: u8,
+    function: u8,
+    address: u16,
+    count: u16,
+    /// result time (UNIX timestamp)
+    t: f64,
+    values: Option<Values>,
+    error: Option<String>,
+}
+
+impl PollConfig {
+    async fn poll(&self, upstream: &Upstream) -> EResult<Values> {
+        let mut req = ModbusRequest::new_tcp_udp(self.unit, 1);
+        let mut request = Vec::new();
+        match self.function {
+            1 => req.generate_get_coils(self.address, self.count, &mut request),
+            2 => req.generate_get_discretes(self.address, self.count, &mut request),
+            3 => req.generate_get_holdings(self.address, self.count, &mut request),
+            4 => req.generate_get_inputs(self.address, self.count, &mut request),
+            v => {
+                return Err(Error::invalid_params(format!(
+                    "unsupported function: {}",
+                    v
+                )))
+            }
+        }
+        .map_err(Error::invalid_params)?;
+        let response = upstream.transact(&request).await?;
+        if self.function < 3 {
+            let mut result = Vec::with_capacity(usize::from(self.count));
+            req.parse_bool(&response, &mut result).map_err(Error::io)?;
+            Ok(Values::Bits(result))
+        } else {
+            let mut result = Vec::with_capacity(usize::from(self.count));
+            req.parse_u16(&response, &mut result).map_err(Error::io)?;
+            Ok(Values::Registers(result))
+        }
+    }
+}
+
+impl MasterConfig {
+    fn validate(&self) -> EResult<()> {
+        for poll in &self.poll {
+            if !(1..=4).contains(&poll.function) {
+                return Err(Error::invalid_params(format!(
+                    "master {}: unsupported function {}",
+                    self.name, poll.function
+                )));
+            }
+            if poll.count == 0 || poll.interval.is_zero() {
+                return Err(Error::invalid_params(format!(
+                    "master {}: count and interval must be positive",
+                    self.name
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Starts polling tasks, the results are published to SVE/<svc_id>/master/<name>
+pub fn launch(config: MasterConfig, svc_id: &str, default_timeout: Duration) -> EResult<()> {
+    config.validate()?;
+    let upstream = Arc::new(Upstream::new(
+        &config.path,
+        config.protocol,
+        config.timeout.unwrap_or(default_timeout),
+    )?);
+    let name = config.name;
+    let topic = Arc::new(format!("SVE/{}/master/{}", svc_id, name));
+    RESULTS
+        .lock()
+        .insert(name.clone(), vec![None; config.poll.len()]);
+    info!("master {} started, slave {}", name, upstream.path());
+    for (idx, poll) in config.poll.into_iter().enumerate() {
+        let upstream = upstream.clone();
+        let topic = topic.clone();
+        let name = name.clone();
+        tokio::spawn(async move {
+            let mut int = tokio::time::interval(poll.interval);
+            int.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                int.tick().await;
+                let (values, error) = match poll.poll(&upstream).await {
+                    Ok(v) => (Some(v), None),
+                    Err(e) => {
+                        error!(
+                            "master {} unit {} function {} address {} error: {}",
+                            name, poll.unit, poll.function, poll.address, e
+                        );
+                        (None, Some(e.to_string()))
+                    }
+                };
+                let result = PollResult {
+                    unit: poll.unit,
+                    function: poll.function,
+                    address: poll.address,
+                    count: poll.count,
+                    t: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0.0, |v| v.as_secs_f64()),
+                    values,
+                    error,
+                };
+                if let Some(r) = RESULTS
+                    .lock()
+                    .get_mut(&name)
+                    .and_then(|results| results.get_mut(idx))
+                {
+                    r.replace(result.clone());
+                }
+                match pack(&result) {
+                    Ok(payload) => {
+                        if let Err(e) = RPC
+                            .get()
+                            .unwrap()
+                            .client()
+                            .lock()
+                            .await
+                            .publish(&topic, payload.into(), QoS::No)
+                            .await
+                        {
+                            error!("master {} publish error: {}", name, e);
+                        }
+                    }
+                    Err(e) => error!("master {} result pack error: {}", name, e),
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Returns the latest poll results of the master or of all masters
+pub fn results(name: Option<&str>) -> EResult<BTreeMap<String, Vec<Option<PollResult>>>> {
+    let results = RESULTS.lock();
+    if let Some(name) = name {
+        let r = results
+            .get(name)
+            .ok_or_else(|| Error::not_found(format!("master {} not found", name)))?;
+        Ok([(name.to_owned(), r.clone())].into())
+    } else {
+        Ok(results.clone())
+    }
+}
@@ -0,0 +1,90 @@
+use crate::framer::MBAP_HEADER_LEN;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// MBAP header, unit id and function code
+const REQUEST_HEAD_LEN: usize = MBAP_HEADER_LEN + 2;
+
+struct PendingRequest {
+    head: [u8; REQUEST_HEAD_LEN],
+    created: Instant,
+}
+
+/// Requests, forwarded to unit services and waiting for replies, by client and transaction id
+static PENDING: Lazy<Mutex<BTreeMap<(Uuid, u16), PendingRequest>>> = Lazy::new(<_>::default);
+
+#[inline]
+fn tid(frame: &[u8]) -> u16 {
+    u16::from_be_bytes([frame[0], frame[1]])
+}
+
+/// Registers a MBAP request frame as outstanding
+///
+/// Returns false if the client has already got an outstanding request with the same
+/// transaction id, the frame is not registered then
+pub fn register(client_id: Uuid, frame: &[u8]) -> bool {
+    let mut head = [0; REQUEST_HEAD_LEN];
+    head.copy_from_slice(&frame[..REQUEST_HEAD_LEN]);
+    match PENDING.lock().entry((client_id, tid(frame))) {
+        Entry::Occupied(_) => false,
+        Entry::Vacant(entry) => {
+            entry.insert(PendingRequest {
+                head,
+                created: Instant::now(),
+            });
+            true
+        }
+    }
+}
+
+/// Marks the request, the MBAP response frame is for, as completed
+///
+/// Returns the request processing time or None if there is no such request (e.g. timed out)
+pub fn complete(client_id: Uuid, frame: &[u8]) -> Option<Duration> {
+    if frame.len() < REQUEST_HEAD_LEN {
+        return None;
+    }
+    PENDING
+        .lock()
+        .remove(&(client_id, tid(frame)))
+        .map(|req| req.created.elapsed())
+}
+
+/// Removes timed out requests and returns their heads
+pub fn take_expired(timeout: Duration) -> Vec<(Uuid, Vec<u8>)> {
+    let mut expired = Vec::new();
+    PENDING.lock().retain(|(client_id, _), req| {
+        if req.created.elapsed() < timeout {
+            true
+        } else {
+            expired.push((*client_id, req.head.to_vec()));
+            false
+        }
+    });
+    expired
+}
+
+#[cfg(test)]
+mod test {
+    use super::{complete, register, take_expired};
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    #[test]
+    fn pending() {
+        let client_id = Uuid::new_v4();
+        assert!(register(client_id, &[0, 1, 0, 0, 0, 6, 1, 3, 0, 0, 0, 1]));
+        assert!(register(client_id, &[0, 2, 0, 0, 0, 6, 1, 4, 0, 0, 0, 1]));
+        // pipelined request with the same transaction id
+        assert!(!register(client_id, &[0, 2, 0, 0, 0, 6, 1, 3, 0, 5, 0, 1]));
+        assert!(complete(client_id, &[0, 1, 0, 0, 0, 5, 1, 3, 2, 0, 0]).is_some());
+        assert!(complete(client_id, &[0, 1, 0, 0, 0, 5, 1, 3, 2, 0, 0]).is_none());
+        let expired = take_expired(Duration::ZERO);
+        assert_eq!(expired, [(client_id, vec![0, 2, 0, 0, 0, 6, 1, 4])]);
+        assert!(take_expired(Duration::ZERO).is_empty());
+    }
+}
@@ -0,0 +1,192 @@
+use crate::framer::FrameError;
+use crate::Protocol;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Listeners are identified by the protocol and the path, as e.g. tcp and udp listeners may
+/// share the same host:port
+pub type ListenerKey = (Protocol, Arc<String>);
+
+/// Listener stats
+static LISTENERS: Lazy<Mutex<BTreeMap<ListenerKey, Stats>>> = Lazy::new(<_>::default);
+
+pub enum Event {
+    FrameIn,
+    FrameOut,
+    BytesIn(usize),
+    BytesOut(usize),
+    CrcError,
+    ParseError,
+    Exception,
+    /// Time between a frame published to bus/in and the reply received from bus/out
+    Latency(Duration),
+}
+
+impl From<&FrameError> for Event {
+    fn from(e: &FrameError) -> Self {
+        match e {
+            FrameError::Checksum => Event::CrcError,
+            FrameError::Invalid(_) | FrameError::OutOfSync(_) => Event::ParseError,
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct Stats {
+    frames_in: u64,
+    frames_out: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    crc_errors: u64,
+    parse_errors: u64,
+    exceptions: u64,
+    latency_count: u64,
+    latency_total: Duration,
+    latency_min: Option<Duration>,
+    latency_max: Option<Duration>,
+}
+
+impl Stats {
+    pub fn apply(&mut self, event: &Event) {
+        match event {
+            Event::FrameIn => self.frames_in += 1,
+            Event::FrameOut => self.frames_out += 1,
+            Event::BytesIn(n) => self.bytes_in += *n as u64,
+            Event::BytesOut(n) => self.bytes_out += *n as u64,
+            Event::CrcError => self.crc_errors += 1,
+            Event::ParseError => self.parse_errors += 1,
+            Event::Exception => self.exceptions += 1,
+            Event::Latency(t) => {
+                self.latency_count += 1;
+                self.latency_total += *t;
+                self.latency_min = Some(self.latency_min.map_or(*t, |v| v.min(*t)));
+                self.latency_max = Some(self.latency_max.map_or(*t, |v| v.max(*t)));
+            }
+        }
+    }
+    #[inline]
+    pub fn frames_in(&self) -> u64 {
+        self.frames_in
+    }
+    #[inline]
+    pub fn frames_out(&self) -> u64 {
+        self.frames_out
+    }
+    pub fn info(&self) -> StatsInfo {
+        #[allow(clippy::cast_possible_truncation)]
+        let latency_avg = if self.latency_count > 0 {
+            Some(self.latency_total / self.latency_count as u32)
+        } else {
+            None
+        };
+        StatsInfo {
+            frames_in: self.frames_in,
+            frames_out: self.frames_out,
+            bytes_in: self.bytes_in,
+            bytes_out: self.bytes_out,
+            crc_errors: self.crc_errors,
+            parse_errors: self.parse_errors,
+            exceptions: self.exceptions,
+            latency_min: self.latency_min.map(|v| v.as_secs_f64()),
+            latency_avg: latency_avg.map(|v| v.as_secs_f64()),
+            latency_max: self.latency_max.map(|v| v.as_secs_f64()),
+        }
+    }
+}
+
+/// Serializable stats, latencies are in seconds
+#[derive(Serialize)]
+pub struct StatsInfo {
+    frames_in: u64,
+    frames_out: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    crc_errors: u64,
+    parse_errors: u64,
+    exceptions: u64,
+    latency_min: Option<f64>,
+    latency_avg: Option<f64>,
+    latency_max: Option<f64>,
+}
+
+/// Serializable listener stats
+#[derive(Serialize)]
+pub struct ListenerStatsInfo {
+    path: Arc<String>,
+    protocol: Protocol,
+    #[serde(flatten)]
+    stats: StatsInfo,
+}
+
+pub fn register_listener(protocol: Protocol, path: Arc<String>) {
+    LISTENERS.lock().entry((protocol, path)).or_default();
+}
+
+pub fn account_listener(protocol: Protocol, path: &Arc<String>, event: &Event) {
+    if let Some(stats) = LISTENERS.lock().get_mut(&(protocol, path.clone())) {
+        stats.apply(event);
+    }
+}
+
+pub fn listeners() -> Vec<ListenerStatsInfo> {
+    LISTENERS
+        .lock()
+        .iter()
+        .map(|((protocol, path), v)| ListenerStatsInfo {
+            path: path.clone(),
+            protocol: *protocol,
+            stats: v.info(),
+        })
+        .collect()
+}
+
+pub fn reset_listeners() {
+    for stats in LISTENERS.lock().values_mut() {
+        *stats = Stats::default();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{account_listener, listeners, register_listener, Event, Stats};
+    use crate::Protocol;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn listener_protocols() {
+        let path = Arc::new("127.0.0.1:15020".to_owned());
+        register_listener(Protocol::Tcp, path.clone());
+        register_listener(Protocol::Udp, path.clone());
+        account_listener(Protocol::Udp, &path, &Event::FrameIn);
+        let stats = listeners();
+        let frames_in = |protocol: Protocol| {
+            stats
+                .iter()
+                .find(|s| s.path == path && s.protocol == protocol)
+                .unwrap()
+                .stats
+                .frames_in
+        };
+        assert_eq!(frames_in(Protocol::Tcp), 0);
+        assert_eq!(frames_in(Protocol::Udp), 1);
+    }
+
+    #[test]
+    fn latency() {
+        let mut stats = Stats::default();
+        assert!(stats.info().latency_avg.is_none());
+        stats.apply(&Event::Latency(Duration::from_millis(10)));
+        stats.apply(&Event::Latency(Duration::from_millis(30)));
+        stats.apply(&Event::FrameIn);
+        let info = stats.info();
+        assert_eq!(info.frames_in, 1);
+        assert_eq!(info.latency_min, Some(0.01));
+        assert_eq!(info.latency_avg, Some(0.02));
+        assert_eq!(info.latency_max, Some(0.03));
+    }
+}
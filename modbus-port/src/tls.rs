@@ -0,0 +1,229 @@
+use eva_common::{EResult, Error};
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslVerifyMode, SslVersion};
+use serde::Deserialize;
+
+/// Modbus/TCP Security role extension OID (1.3.6.1.4.1.50316.802.1), DER-encoded
+const ROLE_OID: [u8; 13] = [
+    0x06, 0x0b, 0x2b, 0x06, 0x01, 0x04, 0x01, 0x83, 0x89, 0x0c, 0x86, 0x22, 0x01,
+];
+
+const ASN1_BOOLEAN: u8 = 0x01;
+const ASN1_OID: u8 = 0x06;
+const ASN1_OCTET_STRING: u8 = 0x04;
+const ASN1_UTF8_STRING: u8 = 0x0c;
+const ASN1_SEQUENCE: u8 = 0x30;
+/// tbsCertificate extensions, [3] EXPLICIT
+const ASN1_EXTENSIONS: u8 = 0xa3;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    cert: String,
+    key: String,
+    /// CA certificates to verify client certificates with
+    #[serde(default)]
+    ca: Option<String>,
+    /// reject clients without a valid certificate
+    #[serde(default)]
+    require_client_cert: bool,
+}
+
+pub fn acceptor(config: &TlsConfig) -> EResult<SslAcceptor> {
+    let mut builder =
+        SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server()).map_err(Error::io)?;
+    // Modbus/TCP Security requires TLS 1.2 at least
+    builder
+        .set_min_proto_version(Some(SslVersion::TLS1_2))
+        .map_err(Error::io)?;
+    builder
+        .set_certificate_chain_file(&config.cert)
+        .map_err(Error::io)?;
+    builder
+        .set_private_key_file(&config.key, SslFiletype::PEM)
+        .map_err(Error::io)?;
+    builder.check_private_key().map_err(Error::io)?;
+    if let Some(ref ca) = config.ca {
+        builder.set_ca_file(ca).map_err(Error::io)?;
+        let mut mode = SslVerifyMode::PEER;
+        if config.require_client_cert {
+            mode |= SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+        }
+        builder.set_verify(mode);
+    } else if config.require_client_cert {
+        return Err(Error::invalid_params(
+            "ca is required to verify client certificates",
+        ));
+    }
+    Ok(builder.build())
+}
+
+/// Reads a DER tag-length header, returns the tag, the content range start and the length
+fn der_header(data: &[u8], pos: usize) -> Option<(u8, usize, usize)> {
+    let tag = *data.get(pos)?;
+    let first = *data.get(pos + 1)?;
+    if first & 0x80 == 0 {
+        return Some((tag, pos + 2, usize::from(first)));
+    }
+    let n = usize::from(first & 0x7f);
+    if n == 0 || n > 4 {
+        return None;
+    }
+    let mut len = 0;
+    for b in data.get(pos + 2..pos + 2 + n)? {
+        len = (len << 8) | usize::from(*b);
+    }
+    Some((tag, pos + 2 + n, len))
+}
+
+/// DER element: the tag, the whole encoding and the content
+type Element<'a> = (u8, &'a [u8], &'a [u8]);
+
+/// Splits DER content into elements, returns None unless the data is fully covered
+fn der_elements(data: &[u8]) -> Option<Vec<Element<'_>>> {
+    let mut result = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let (tag, start, len) = der_header(data, pos)?;
+        let end = start.checked_add(len)?;
+        result.push((tag, data.get(pos..end)?, data.get(start..end)?));
+        pos = end;
+    }
+    Some(result)
+}
+
+/// Returns the content of a single DER element with the expected tag, which fills the data
+fn der_single(data: &[u8], tag: u8) -> Option<&[u8]> {
+    match der_elements(data)?.as_slice() {
+        [(t, _, content)] if *t == tag => Some(content),
+        _ => None,
+    }
+}
+
+/// Returns the extension values (without the optional critical flags) of a DER-encoded
+/// certificate
+fn cert_extensions(der: &[u8]) -> Option<Vec<Element<'_>>> {
+    let cert = der_single(der, ASN1_SEQUENCE)?;
+    let tbs = match der_elements(cert)?.first() {
+        Some((ASN1_SEQUENCE, _, content)) => *content,
+        _ => return None,
+    };
+    let mut result = Vec::new();
+    let Some((_, _, extensions)) = der_elements(tbs)?
+        .into_iter()
+        .find(|(tag, _, _)| *tag == ASN1_EXTENSIONS)
+    else {
+        // v1/v2 certificates have no extensions
+        return Some(result);
+    };
+    for (tag, _, extension) in der_elements(der_single(extensions, ASN1_SEQUENCE)?)? {
+        if tag != ASN1_SEQUENCE {
+            return None;
+        }
+        let extension = der_elements(extension)?;
+        let (oid, value) = match extension.as_slice() {
+            [oid, value] | [oid, (ASN1_BOOLEAN, _, _), value] => (oid, value),
+            _ => return None,
+        };
+        if oid.0 != ASN1_OID || value.0 != ASN1_OCTET_STRING {
+            return None;
+        }
+        result.push((oid.0, oid.1, value.2));
+    }
+    Some(result)
+}
+
+/// Extracts the Modbus/TCP Security role from a DER-encoded client certificate, the role
+/// extension must contain a single UTF8String
+pub fn cert_role(der: &[u8]) -> EResult<Option<String>> {
+    let extensions =
+        cert_extensions(der).ok_or_else(|| Error::invalid_data("invalid certificate"))?;
+    let mut roles = extensions
+        .into_iter()
+        .filter(|(_, oid, _)| *oid == ROLE_OID)
+        .map(|(_, _, value)| value);
+    let Some(value) = roles.next() else {
+        return Ok(None);
+    };
+    if roles.next().is_some() {
+        return Err(Error::invalid_data("duplicate role extension"));
+    }
+    let role = der_single(value, ASN1_UTF8_STRING)
+        .ok_or_else(|| Error::invalid_data("the role extension is not a UTF8String"))?;
+    String::from_utf8(role.to_vec())
+        .map(Some)
+        .map_err(|_| Error::invalid_data("the role extension is not a valid UTF-8 string"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{cert_role, ROLE_OID};
+
+    fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut result = vec![tag];
+        if content.len() < 0x80 {
+            result.push(u8::try_from(content.len()).unwrap());
+        } else {
+            result.push(0x82);
+            result.extend(u16::try_from(content.len()).unwrap().to_be_bytes());
+        }
+        result.extend(content);
+        result
+    }
+
+    /// Builds a certificate-shaped DER with the subject and the extensions given
+    fn cert(subject: &[u8], extensions: &[Vec<u8>]) -> Vec<u8> {
+        let mut tbs = tlv(0xa0, &[0x02, 0x01, 0x02]);
+        tbs.extend([0x02, 0x01, 0x01]);
+        tbs.extend(tlv(0x30, &[]));
+        tbs.extend(tlv(0x30, &[]));
+        tbs.extend(tlv(0x30, &[]));
+        tbs.extend(tlv(0x30, subject));
+        tbs.extend(tlv(0x30, &[]));
+        if !extensions.is_empty() {
+            tbs.extend(tlv(0xa3, &tlv(0x30, &extensions.concat())));
+        }
+        let mut cert = tlv(0x30, &tbs);
+        cert.extend(tlv(0x30, &[]));
+        cert.extend([0x03, 0x01, 0x00]);
+        tlv(0x30, &cert)
+    }
+
+    fn role_ext(critical: bool, value: &[u8]) -> Vec<u8> {
+        let mut ext = ROLE_OID.to_vec();
+        if critical {
+            ext.extend([0x01, 0x01, 0x00]);
+        }
+        ext.extend(tlv(0x04, value));
+        tlv(0x30, &ext)
+    }
+
+    #[test]
+    fn role() {
+        let der = cert(&[], &[role_ext(true, &tlv(0x0c, b"writer"))]);
+        assert_eq!(cert_role(&der).unwrap().as_deref(), Some("writer"));
+        let other = tlv(
+            0x30,
+            &[
+                &tlv(0x06, &[0x55, 0x1d, 0x13])[..],
+                &tlv(0x04, &[0x30, 0x00]),
+            ]
+            .concat(),
+        );
+        let der = cert(&[], &[other, role_ext(false, &tlv(0x0c, b"reader"))]);
+        assert_eq!(cert_role(&der).unwrap().as_deref(), Some("reader"));
+        assert!(cert_role(&der[..der.len() - 1]).is_err());
+        assert!(cert_role(&cert(&[], &[])).unwrap().is_none());
+        // the role OID and a string outside of the extensions are ignored
+        let mut subject = ROLE_OID.to_vec();
+        subject.extend(tlv(0x04, &tlv(0x0c, b"admin")));
+        assert!(cert_role(&cert(&subject, &[])).unwrap().is_none());
+        // malformed role values are rejected
+        assert!(cert_role(&cert(&[], &[role_ext(false, &tlv(0x13, b"writer"))])).is_err());
+        let mut value = tlv(0x0c, b"writer");
+        value.push(0);
+        assert!(cert_role(&cert(&[], &[role_ext(false, &value)])).is_err());
+        assert!(cert_role(&cert(&[], &[role_ext(false, &tlv(0x0c, &[0xff]))])).is_err());
+        let ext = role_ext(false, &tlv(0x0c, b"writer"));
+        assert!(cert_role(&cert(&[], &[ext.clone(), ext])).is_err());
+    }
+}
@@ -0,0 +1,88 @@
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const UNIT_CLEAN_INTERVAL: Duration = Duration::from_secs(1);
+const UNIT_EXPIRED: Duration = Duration::from_secs(30);
+
+struct UnitEntry {
+    svc_id: Arc<String>,
+    last_alive: Instant,
+}
+
+static UNITS: Lazy<Mutex<BTreeMap<u8, UnitEntry>>> = Lazy::new(<_>::default);
+
+#[derive(Serialize)]
+pub struct UnitInfo {
+    unit: u8,
+    svc_id: Arc<String>,
+}
+
+/// Registers the unit or refreshes its registration
+pub fn ping(unit: u8, svc_id: &str) {
+    let mut units = UNITS.lock();
+    if let Some(entry) = units.get_mut(&unit) {
+        if entry.svc_id.as_str() != svc_id {
+            warn!(
+                "unit {} registration moved from {} to {}",
+                unit, entry.svc_id, svc_id
+            );
+            entry.svc_id = svc_id.to_owned().into();
+        }
+        entry.last_alive = Instant::now();
+    } else {
+        info!("unit {} registered by {}", unit, svc_id);
+        units.insert(
+            unit,
+            UnitEntry {
+                svc_id: svc_id.to_owned().into(),
+                last_alive: Instant::now(),
+            },
+        );
+    }
+}
+
+pub fn unregister(unit: u8, svc_id: &str) {
+    let mut units = UNITS.lock();
+    if units
+        .get(&unit)
+        .is_some_and(|entry| entry.svc_id.as_str() == svc_id)
+    {
+        units.remove(&unit);
+        info!("unit {} unregistered by {}", unit, svc_id);
+    }
+}
+
+#[inline]
+pub fn is_registered(unit: u8) -> bool {
+    UNITS.lock().contains_key(&unit)
+}
+
+pub fn list() -> Vec<UnitInfo> {
+    UNITS
+        .lock()
+        .iter()
+        .map(|(k, v)| UnitInfo {
+            unit: *k,
+            svc_id: v.svc_id.clone(),
+        })
+        .collect()
+}
+
+pub async fn cleaner() {
+    let mut int = tokio::time::interval(UNIT_CLEAN_INTERVAL);
+    loop {
+        UNITS.lock().retain(|unit, v| {
+            let alive = v.last_alive.elapsed() < UNIT_EXPIRED;
+            if !alive {
+                warn!("unit {} registration expired ({})", unit, v.svc_id);
+            }
+            alive
+        });
+        int.tick().await;
+    }
+}
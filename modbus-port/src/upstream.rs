@@ -0,0 +1,210 @@
+use crate::framer::{crc_valid, mbap_to_rtu, rtu_response_len, rtu_to_mbap, MBAP_HEADER_LEN};
+use crate::serial_port;
+use eva_common::{EResult, Error};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_serial::{SerialPortBuilder, SerialPortBuilderExt, SerialStream};
+
+/// Pause after an unknown-length RTU response is considered complete
+const RTU_RESPONSE_SILENCE: Duration = Duration::from_millis(50);
+
+#[derive(Deserialize, Serialize, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamProtocol {
+    Tcp,
+    Rtu,
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    Rtu(SerialStream),
+}
+
+/// Remote Modbus slave (TCP or RTU), requests are processed one by one
+pub struct Upstream {
+    path: String,
+    protocol: UpstreamProtocol,
+    serial: Option<SerialPortBuilder>,
+    timeout: Duration,
+    tid: atomic::AtomicU16,
+    conn: Mutex<Option<Connection>>,
+}
+
+impl Upstream {
+    pub fn new(path: &str, protocol: UpstreamProtocol, timeout: Duration) -> EResult<Self> {
+        let serial = if protocol == UpstreamProtocol::Rtu {
+            Some(serial_port(path)?.0)
+        } else {
+            None
+        };
+        Ok(Self {
+            path: path.to_owned(),
+            protocol,
+            serial,
+            timeout,
+            tid: atomic::AtomicU16::new(0),
+            conn: <_>::default(),
+        })
+    }
+    #[inline]
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+    async fn connect(&self) -> EResult<Connection> {
+        match self.protocol {
+            UpstreamProtocol::Tcp => {
+                let stream =
+                    tokio::time::timeout(self.timeout, TcpStream::connect(&self.path)).await??;
+                stream.set_nodelay(true)?;
+                Ok(Connection::Tcp(stream))
+            }
+            UpstreamProtocol::Rtu => Ok(Connection::Rtu(
+                self.serial
+                    .clone()
+                    .unwrap()
+                    .open_native_async()
+                    .map_err(Error::io)?,
+            )),
+        }
+    }
+    /// Sends a MBAP request frame and returns the MBAP response frame with the transaction id
+    /// of the request
+    ///
+    /// The connection is (re)opened on demand and closed on errors
+    pub async fn transact(&self, frame: &[u8]) -> EResult<Vec<u8>> {
+        if frame.len() <= MBAP_HEADER_LEN + 1 {
+            return Err(Error::invalid_data("request frame too short"));
+        }
+        let mut conn = self.conn.lock().await;
+        if conn.is_none() {
+            conn.replace(self.connect().await?);
+        }
+        let result = tokio::time::timeout(self.timeout, async {
+            match conn.as_mut().unwrap() {
+                Connection::Tcp(stream) => self.transact_tcp(stream, frame).await,
+                Connection::Rtu(stream) => transact_rtu(stream, frame).await,
+            }
+        })
+        .await
+        .map_err(Into::into)
+        .and_then(|r| r);
+        if let Err(ref e) = result {
+            warn!("upstream {} error: {}, disconnecting", self.path, e);
+            conn.take();
+        }
+        result
+    }
+    async fn transact_tcp(&self, stream: &mut TcpStream, frame: &[u8]) -> EResult<Vec<u8>> {
+        let tid = self
+            .tid
+            .fetch_add(1, atomic::Ordering::Relaxed)
+            .wrapping_add(1);
+        let mut request = frame.to_vec();
+        request[..2].copy_from_slice(&tid.to_be_bytes());
+        stream.write_all(&request).await?;
+        loop {
+            let mut header = [0_u8; MBAP_HEADER_LEN];
+            stream.read_exact(&mut header).await?;
+            let len = usize::from(u16::from_be_bytes([header[4], header[5]]));
+            if len == 0 || len > 254 {
+                return Err(Error::invalid_data("invalid upstream frame length"));
+            }
+            let mut response = Vec::with_capacity(MBAP_HEADER_LEN + len);
+            response.extend(header);
+            response.resize(MBAP_HEADER_LEN + len, 0);
+            stream.read_exact(&mut response[MBAP_HEADER_LEN..]).await?;
+            // skip late replies to previous (timed out) requests
+            if header[..2] == tid.to_be_bytes() {
+                response[..2].copy_from_slice(&frame[..2]);
+                return Ok(response);
+            }
+        }
+    }
+}
+
+async fn transact_rtu(stream: &mut SerialStream, frame: &[u8]) -> EResult<Vec<u8>> {
+    let request = mbap_to_rtu(frame).unwrap();
+    // drop garbage left from previous requests
+    let mut buf = [0_u8; 256];
+    while let Ok(Ok(n)) = tokio::time::timeout(Duration::ZERO, stream.read(&mut buf)).await {
+        if n == 0 {
+            break;
+        }
+    }
+    stream.write_all(&request).await?;
+    let mut response = Vec::new();
+    loop {
+        if let Some(len) = rtu_response_len(&response) {
+            if response.len() >= len {
+                response.truncate(len);
+                break;
+            }
+        }
+        let n = if rtu_response_len(&response).is_none() && response.len() >= 2 {
+            // unknown response length, read until silence
+            match tokio::time::timeout(RTU_RESPONSE_SILENCE, stream.read(&mut buf)).await {
+                Ok(v) => v?,
+                Err(_) => break,
+            }
+        } else {
+            stream.read(&mut buf).await?
+        };
+        if n == 0 {
+            return Err(Error::io("upstream port closed"));
+        }
+        response.extend(&buf[..n]);
+    }
+    if !crc_valid(&response) {
+        return Err(Error::invalid_data("upstream frame CRC error"));
+    }
+    if response[0] != frame[MBAP_HEADER_LEN] {
+        return Err(Error::invalid_data("upstream unit id mismatch"));
+    }
+    response.truncate(response.len() - 2);
+    let tid = u16::from_be_bytes([frame[0], frame[1]]);
+    Ok(rtu_to_mbap(tid, &response))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Upstream, UpstreamProtocol};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn tcp_transact() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0_u8; 12];
+            stream.read_exact(&mut buf).await.unwrap();
+            // a stale reply first
+            stream
+                .write_all(&[0xff, 0xff, 0, 0, 0, 5, 1, 3, 2, 0, 0])
+                .await
+                .unwrap();
+            stream
+                .write_all(&[buf[0], buf[1], 0, 0, 0, 5, 1, 3, 2, 0, 42])
+                .await
+                .unwrap();
+        });
+        let upstream = Upstream::new(
+            &addr.to_string(),
+            UpstreamProtocol::Tcp,
+            Duration::from_secs(1),
+        )
+        .unwrap();
+        let response = upstream
+            .transact(&[0x12, 0x34, 0, 0, 0, 6, 1, 3, 0, 0, 0, 1])
+            .await
+            .unwrap();
+        assert_eq!(response, [0x12, 0x34, 0, 0, 0, 5, 1, 3, 2, 0, 42]);
+    }
+}
@@ -1,33 +1,97 @@
 use eva_common::prelude::*;
 use eva_sdk::bitman::BitMan;
 use eva_sdk::prelude::*;
-use once_cell::sync::{Lazy, OnceCell};
-use rmodbus::server::context::ModbusContext;
+use eva_sim_modbus::{AccessRule, Device, Identity, UnitService};
+use rmodbus::server::context::{ModbusContext, FULL_CONTEXT_SIZE};
 use serde::Deserialize;
 use std::collections::BTreeMap;
 use std::sync::Arc;
-use tokio::sync::Mutex;
 
 const AUTHOR: &str = "Bohemia Automation";
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const DESCRIPTION: &str = "SIM Virtual Modbus relay";
 
-static RPC: OnceCell<Arc<RpcClient>> = OnceCell::new();
-static CONTEXT: Lazy<Mutex<ModbusContext<8, 0, 0, 1>>> = Lazy::new(<_>::default);
-
 #[cfg(not(feature = "std-alloc"))]
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
 err_logger!();
 
-struct Handlers {
-    info: ServiceInfo,
-    unit: u8,
+type Unit = UnitService<Relay, 10_000, 0, 0, 10_000>;
+
+const DEFAULT_PORTS: u16 = 8;
+
+const EXCEPTION_ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+
+struct Relay {
     reg: Reg,
+    offset: u16,
+    ports: u16,
     output_type: OutputType,
 }
 
+impl Relay {
+    /// Registers or coils occupied by the ports
+    fn size(&self) -> u16 {
+        match self.reg {
+            Reg::Holding => self.ports.div_ceil(16),
+            Reg::Coil => self.ports,
+        }
+    }
+    /// Denies client requests outside of the port registers/coils
+    fn window_exception(&self, func: u8, reg: u16, count: u16) -> Option<u8> {
+        let reg_ok = match self.reg {
+            Reg::Holding => matches!(func, 3 | 6 | 16 | 0x16 | 0x18),
+            Reg::Coil => matches!(func, 1 | 5 | 15 | 0x07),
+        };
+        let in_window = u32::from(reg) >= u32::from(self.offset)
+            && u32::from(reg) + u32::from(count) <= u32::from(self.offset) + u32::from(self.size());
+        (!reg_ok || !in_window).then_some(EXCEPTION_ILLEGAL_DATA_ADDRESS)
+    }
+}
+
+impl Device<10_000, 0, 0, 10_000> for Relay {
+    fn var_get(&self, ctx: &ModbusContext<10_000, 0, 0, 10_000>, params: Value) -> EResult<Value> {
+        if params != Value::Unit {
+            return Err(Error::invalid_params("no params required"));
+        }
+        let mut result: BTreeMap<String, Value> = BTreeMap::new();
+        let mut data = Vec::with_capacity(usize::from(self.ports));
+        match self.reg {
+            Reg::Holding => {
+                // bit per port, 16 ports per register
+                for i in 0..self.ports {
+                    let val = ctx.get_holding(self.offset + i / 16).unwrap();
+                    data.push(val.get_bit(u32::from(i % 16)));
+                }
+            }
+            Reg::Coil => {
+                ctx.get_coils_bulk(self.offset, self.ports, &mut data)
+                    .unwrap();
+            }
+        }
+        for (port, val) in data.into_iter().enumerate() {
+            let value = match self.output_type {
+                OutputType::Boolean => Value::Bool(val),
+                OutputType::Number => Value::U8(u8::from(val)),
+            };
+            result.insert(format!("port{}", port + 1), value);
+        }
+        Ok(to_value(result)?)
+    }
+    fn write_exception(&self, func: u8, reg: u16, count: u16) -> Option<u8> {
+        self.window_exception(func, reg, count)
+    }
+    fn read_exception(&self, func: u8, reg: u16, count: u16) -> Option<u8> {
+        self.window_exception(func, reg, count)
+    }
+}
+
+struct Handlers {
+    info: ServiceInfo,
+    unit: Arc<Unit>,
+}
+
 #[async_trait::async_trait]
 impl RpcHandlers for Handlers {
     // Handle RPC call
@@ -35,49 +99,16 @@ impl RpcHandlers for Handlers {
         svc_rpc_need_ready!();
         let method = event.parse_method()?;
         let payload = event.payload();
-        #[allow(clippy::match_single_binding)]
         match method {
-            "var.get" =>
-            {
-                #[allow(clippy::cast_possible_wrap)]
-                if payload.is_empty() {
-                    let ctx = CONTEXT.lock().await;
-                    let mut result: BTreeMap<String, Value> = BTreeMap::new();
-                    let mut data = Vec::with_capacity(8);
-                    match self.reg {
-                        Reg::Holding => {
-                            let val = ctx.get_holding(0).unwrap();
-                            for i in 0..8 {
-                                data.push(val.get_bit(i));
-                            }
-                        }
-                        Reg::Coil => {
-                            ctx.get_coils_bulk(0, 8, &mut data).unwrap();
-                        }
-                    }
-                    for (port, val) in data.into_iter().enumerate() {
-                        let value = match self.output_type {
-                            OutputType::Boolean => Value::Bool(val),
-                            OutputType::Number => Value::U8(u8::from(val)),
-                        };
-                        result.insert(format!("port{}", port + 1), value);
-                    }
-                    Ok(Some(pack(&result)?))
-                } else {
-                    Err(RpcError::params(None))
-                }
+            "var.get" => {
+                let value = self.unit.var_get(eva_sim_modbus::params(payload)?).await?;
+                Ok(Some(pack(&value)?))
             }
             _ => svc_handle_default_rpc(method, &self.info),
         }
     }
     async fn handle_frame(&self, frame: Frame) {
-        eva_sim_modbus::process_modbus_frame::<8, 0, 0, 1>(
-            frame,
-            &mut *CONTEXT.lock().await,
-            self.unit,
-            RPC.get().unwrap().as_ref(),
-        )
-        .await;
+        self.unit.handle_frame(&frame).await;
     }
 }
 
@@ -87,9 +118,27 @@ struct Config {
     port_svc: String,
     unit: u8,
     #[serde(default)]
+    write_roles: Option<Vec<String>>,
+    #[serde(default)]
     reg: Reg,
+    /// the first register/coil
+    #[serde(default)]
+    offset: u16,
+    #[serde(default = "default_ports")]
+    ports: u16,
     #[serde(default)]
     output_type: OutputType,
+    /// device identification objects (FC 0x11, 0x2B/0x0E)
+    #[serde(default)]
+    identity: Identity,
+    /// register-level access rules for clients
+    #[serde(default)]
+    access: Vec<AccessRule>,
+}
+
+#[inline]
+fn default_ports() -> u16 {
+    DEFAULT_PORTS
 }
 
 #[derive(Deserialize, Default, Copy, Clone)]
@@ -117,26 +166,73 @@ async fn main(mut initial: Initial) -> EResult<()> {
             .take_config()
             .ok_or_else(|| Error::invalid_data("config not specified"))?,
     )?;
+    let timeout = initial.timeout();
     let mut info = ServiceInfo::new(AUTHOR, VERSION, DESCRIPTION);
     info.add_method(ServiceMethod::new("var.get"));
+    let relay = Relay {
+        reg: config.reg,
+        offset: config.offset,
+        ports: config.ports,
+        output_type: config.output_type,
+    };
+    if relay.ports == 0 {
+        return Err(Error::invalid_params("at least one port required"));
+    }
+    if usize::from(relay.offset) + usize::from(relay.size()) > FULL_CONTEXT_SIZE {
+        return Err(Error::invalid_params("offset out of range"));
+    }
+    let unit = Arc::new(
+        Unit::new(&config.port_svc, config.unit, relay)
+            .write_roles(config.write_roles)
+            .identity(config.identity)
+            .access(config.access),
+    );
     let rpc = initial
         .init_rpc(Handlers {
             info,
-            unit: config.unit,
-            reg: config.reg,
-            output_type: config.output_type,
+            unit: unit.clone(),
         })
         .await?;
     initial.drop_privileges()?;
     let client = rpc.client().clone();
-    RPC.set(rpc.clone())
-        .map_err(|_| Error::core("Unable to set RPC"))?;
-    eva_sim_modbus::init(&config.port_svc, &mut *client.lock().await).await?;
     svc_init_logs(&initial, client.clone())?;
     svc_start_signal_handlers();
+    unit.start(rpc.clone(), timeout).await?;
     svc_mark_ready(&client).await?;
     info!("{} started ({})", DESCRIPTION, initial.id());
     svc_block(&rpc).await;
     svc_mark_terminating(&client).await?;
+    unit.stop(timeout).await;
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::{OutputType, Reg, Relay};
+    use eva_sim_modbus::Device;
+
+    #[test]
+    fn window() {
+        let relay = Relay {
+            reg: Reg::Coil,
+            offset: 100,
+            ports: 8,
+            output_type: OutputType::Boolean,
+        };
+        assert_eq!(relay.read_exception(1, 100, 8), None);
+        assert_eq!(relay.read_exception(1, 0, 1), Some(0x02));
+        assert_eq!(relay.read_exception(1, 104, 5), Some(0x02));
+        assert_eq!(relay.read_exception(3, 100, 1), Some(0x02));
+        assert_eq!(relay.write_exception(5, 107, 1), None);
+        assert_eq!(relay.write_exception(5, 108, 1), Some(0x02));
+        let relay = Relay {
+            reg: Reg::Holding,
+            offset: 10,
+            ports: 20,
+            output_type: OutputType::Boolean,
+        };
+        assert_eq!(relay.read_exception(3, 10, 2), None);
+        assert_eq!(relay.read_exception(3, 10, 3), Some(0x02));
+        assert_eq!(relay.write_exception(6, 9, 1), Some(0x02));
+    }
+}
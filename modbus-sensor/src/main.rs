@@ -1,40 +1,43 @@
 use eva_common::prelude::*;
 use eva_sdk::prelude::*;
-use once_cell::sync::{Lazy, OnceCell};
-use rmodbus::server::context::ModbusContext;
+use eva_sim_modbus::data::{DataType, Encoding};
+use eva_sim_modbus::{AccessRule, Device, Identity, UnitService};
+use rmodbus::server::context::{ModbusContext, FULL_CONTEXT_SIZE};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tokio::sync::Mutex;
 
 const AUTHOR: &str = "Bohemia Automation";
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const DESCRIPTION: &str = "SIM Virtual Modbus sensor";
 
-static RPC: OnceCell<Arc<RpcClient>> = OnceCell::new();
-static CONTEXT: Lazy<Mutex<ModbusContext<0, 0, 4, 4>>> = Lazy::new(<_>::default);
-
 #[cfg(not(feature = "std-alloc"))]
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
 err_logger!();
 
-trait SwapModbusEndianess {
-    fn to_swapped_modbus_endianness(&self) -> Self;
-}
+type Unit = UnitService<Sensor, 0, 0, 10_000, 10_000>;
 
-impl SwapModbusEndianess for f32 {
-    fn to_swapped_modbus_endianness(&self) -> Self {
-        let b = self.to_be_bytes();
-        Self::from_be_bytes([b[2], b[3], b[0], b[1]])
-    }
-}
+const EXCEPTION_ILLEGAL_DATA_ADDRESS: u8 = 0x02;
 
-struct Handlers {
-    info: ServiceInfo,
-    unit: u8,
-    tp: DataType,
+struct Sensor {
+    encoding: Encoding,
     reg: Reg,
+    offset: u16,
+}
+
+impl Sensor {
+    /// Denies client requests outside of the value registers
+    fn window_exception(&self, func: u8, reg: u16, count: u16) -> Option<u8> {
+        let reg_ok = match self.reg {
+            Reg::Holding => matches!(func, 3 | 6 | 16 | 0x16 | 0x18),
+            Reg::Input => func == 4,
+        };
+        let in_window = u32::from(reg) >= u32::from(self.offset)
+            && u32::from(reg) + u32::from(count)
+                <= u32::from(self.offset) + u32::from(self.encoding.registers());
+        (!reg_ok || !in_window).then_some(EXCEPTION_ILLEGAL_DATA_ADDRESS)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -42,127 +45,60 @@ struct ValuePayload {
     value: Value,
 }
 
+impl Device<0, 0, 10_000, 10_000> for Sensor {
+    fn var_get(&self, ctx: &ModbusContext<0, 0, 10_000, 10_000>, params: Value) -> EResult<Value> {
+        if params != Value::Unit {
+            return Err(Error::invalid_params("no params required"));
+        }
+        let count = self.encoding.registers();
+        let mut regs = Vec::with_capacity(usize::from(count));
+        match self.reg {
+            Reg::Holding => ctx.get_holdings_bulk(self.offset, count, &mut regs),
+            Reg::Input => ctx.get_inputs_bulk(self.offset, count, &mut regs),
+        }
+        .map_err(Error::failed)?;
+        let value = self.encoding.decode(&regs)?;
+        Ok(to_value(ValuePayload { value })?)
+    }
+    fn var_set(&self, ctx: &mut ModbusContext<0, 0, 10_000, 10_000>, params: Value) -> EResult<()> {
+        let p = ValuePayload::deserialize(params)?;
+        let regs = self.encoding.encode(p.value)?;
+        match self.reg {
+            Reg::Holding => ctx.set_holdings_bulk(self.offset, &regs),
+            Reg::Input => ctx.set_inputs_bulk(self.offset, &regs),
+        }
+        .map_err(Error::failed)?;
+        Ok(())
+    }
+    fn write_exception(&self, func: u8, reg: u16, count: u16) -> Option<u8> {
+        self.window_exception(func, reg, count)
+    }
+    fn read_exception(&self, func: u8, reg: u16, count: u16) -> Option<u8> {
+        self.window_exception(func, reg, count)
+    }
+}
+
+struct Handlers {
+    info: ServiceInfo,
+    unit: Arc<Unit>,
+}
+
 #[async_trait::async_trait]
 impl RpcHandlers for Handlers {
-    #[allow(clippy::cast_sign_loss, clippy::too_many_lines)]
     async fn handle_call(&self, event: RpcEvent) -> RpcResult {
         svc_rpc_need_ready!();
         let method = event.parse_method()?;
         let payload = event.payload();
-        #[allow(clippy::match_single_binding)]
         match method {
-            "var.get" =>
-            {
-                #[allow(clippy::cast_possible_wrap)]
-                if payload.is_empty() {
-                    let ctx = CONTEXT.lock().await;
-                    let value = match self.tp {
-                        DataType::Int => Value::I16(match self.reg {
-                            Reg::Holding => ctx.get_holding(0).unwrap() as i16,
-                            Reg::Input => ctx.get_input(0).unwrap() as i16,
-                        }),
-                        DataType::Uint => Value::U16(match self.reg {
-                            Reg::Holding => ctx.get_holding(0).unwrap(),
-                            Reg::Input => ctx.get_input(0).unwrap(),
-                        }),
-                        DataType::Dint => Value::I32(match self.reg {
-                            Reg::Holding => ctx.get_holdings_as_u32(0).unwrap() as i32,
-                            Reg::Input => ctx.get_inputs_as_u32(0).unwrap() as i32,
-                        }),
-                        DataType::Udint => Value::U32(match self.reg {
-                            Reg::Holding => ctx.get_holdings_as_u32(0).unwrap(),
-                            Reg::Input => ctx.get_inputs_as_u32(0).unwrap(),
-                        }),
-                        DataType::Lint => Value::I64(match self.reg {
-                            Reg::Holding => ctx.get_holdings_as_u64(0).unwrap() as i64,
-                            Reg::Input => ctx.get_inputs_as_u64(0).unwrap() as i64,
-                        }),
-                        DataType::Ulint => Value::U64(match self.reg {
-                            Reg::Holding => ctx.get_holdings_as_u64(0).unwrap(),
-                            Reg::Input => ctx.get_inputs_as_u64(0).unwrap(),
-                        }),
-                        DataType::Real => Value::F32(
-                            match self.reg {
-                                Reg::Holding => ctx.get_holdings_as_f32(0).unwrap(),
-                                Reg::Input => ctx.get_inputs_as_f32(0).unwrap(),
-                            }
-                            .to_swapped_modbus_endianness(),
-                        ),
-                        DataType::Realb => Value::F32(match self.reg {
-                            Reg::Holding => ctx.get_holdings_as_f32(0).unwrap(),
-                            Reg::Input => ctx.get_inputs_as_f32(0).unwrap(),
-                        }),
-                    };
-                    Ok(Some(pack(&ValuePayload { value })?))
-                } else {
-                    Err(RpcError::params(None))
-                }
+            "var.get" => {
+                let value = self.unit.var_get(eva_sim_modbus::params(payload)?).await?;
+                Ok(Some(pack(&value)?))
             }
             "var.set" => {
                 if payload.is_empty() {
                     Err(RpcError::params(None))
                 } else {
-                    let p: ValuePayload = unpack(payload)?;
-                    let mut ctx = CONTEXT.lock().await;
-                    match self.tp {
-                        DataType::Int => {
-                            let val: i16 = p.value.try_into()?;
-                            match self.reg {
-                                Reg::Holding => ctx.set_holding(0, val as u16).unwrap(),
-                                Reg::Input => ctx.set_input(0, val as u16).unwrap(),
-                            }
-                        }
-                        DataType::Uint => {
-                            let val: u16 = p.value.try_into()?;
-                            match self.reg {
-                                Reg::Holding => ctx.set_holding(0, val).unwrap(),
-                                Reg::Input => ctx.set_input(0, val).unwrap(),
-                            }
-                        }
-                        DataType::Dint => {
-                            let val: i32 = p.value.try_into()?;
-                            match self.reg {
-                                Reg::Holding => ctx.set_holdings_from_u32(0, val as u32).unwrap(),
-                                Reg::Input => ctx.set_inputs_from_u32(0, val as u32).unwrap(),
-                            }
-                        }
-                        DataType::Udint => {
-                            let val: u32 = p.value.try_into()?;
-                            match self.reg {
-                                Reg::Holding => ctx.set_holdings_from_u32(0, val).unwrap(),
-                                Reg::Input => ctx.set_inputs_from_u32(0, val).unwrap(),
-                            }
-                        }
-                        DataType::Lint => {
-                            let val: i64 = p.value.try_into()?;
-                            match self.reg {
-                                Reg::Holding => ctx.set_holdings_from_u64(0, val as u64).unwrap(),
-                                Reg::Input => ctx.set_inputs_from_u64(0, val as u64).unwrap(),
-                            }
-                        }
-                        DataType::Ulint => {
-                            let val: u64 = p.value.try_into()?;
-                            match self.reg {
-                                Reg::Holding => ctx.set_holdings_from_u64(0, val).unwrap(),
-                                Reg::Input => ctx.set_inputs_from_u64(0, val).unwrap(),
-                            }
-                        }
-                        DataType::Real => {
-                            let v: f32 = p.value.try_into()?;
-                            let val = v.to_swapped_modbus_endianness();
-                            match self.reg {
-                                Reg::Holding => ctx.set_holdings_from_f32(0, val).unwrap(),
-                                Reg::Input => ctx.set_inputs_from_f32(0, val).unwrap(),
-                            }
-                        }
-                        DataType::Realb => {
-                            let val: f32 = p.value.try_into()?;
-                            match self.reg {
-                                Reg::Holding => ctx.set_holdings_from_f32(0, val).unwrap(),
-                                Reg::Input => ctx.set_inputs_from_f32(0, val).unwrap(),
-                            }
-                        }
-                    };
+                    self.unit.var_set(unpack(payload)?).await?;
                     Ok(None)
                 }
             }
@@ -170,13 +106,7 @@ impl RpcHandlers for Handlers {
         }
     }
     async fn handle_frame(&self, frame: Frame) {
-        eva_sim_modbus::process_modbus_frame::<0, 0, 4, 4>(
-            frame,
-            &mut *CONTEXT.lock().await,
-            self.unit,
-            RPC.get().unwrap().as_ref(),
-        )
-        .await;
+        self.unit.handle_frame(&frame).await;
     }
 }
 
@@ -185,24 +115,21 @@ impl RpcHandlers for Handlers {
 struct Config {
     port_svc: String,
     unit: u8,
+    #[serde(default)]
+    write_roles: Option<Vec<String>>,
     #[serde(default, rename = "type")]
     tp: DataType,
     #[serde(default)]
     reg: Reg,
-}
-
-#[derive(Deserialize, Default, Copy, Clone)]
-#[serde(rename_all = "UPPERCASE")]
-enum DataType {
-    #[default]
-    Int,
-    Uint,
-    Dint,
-    Udint,
-    Lint,
-    Ulint,
-    Real,
-    Realb,
+    /// the first register
+    #[serde(default)]
+    offset: u16,
+    /// device identification objects (FC 0x11, 0x2B/0x0E)
+    #[serde(default)]
+    identity: Identity,
+    /// register-level access rules for clients
+    #[serde(default)]
+    access: Vec<AccessRule>,
 }
 
 #[derive(Deserialize, Default, Copy, Clone)]
@@ -222,27 +149,59 @@ async fn main(mut initial: Initial) -> EResult<()> {
             .take_config()
             .ok_or_else(|| Error::invalid_data("config not specified"))?,
     )?;
+    let timeout = initial.timeout();
     let mut info = ServiceInfo::new(AUTHOR, VERSION, DESCRIPTION);
     info.add_method(ServiceMethod::new("var.get"));
     info.add_method(ServiceMethod::new("var.set").required("value"));
+    let sensor = Sensor {
+        encoding: Encoding::new(config.tp).string_len(4),
+        reg: config.reg,
+        offset: config.offset,
+    };
+    if usize::from(sensor.offset) + usize::from(sensor.encoding.registers()) > FULL_CONTEXT_SIZE {
+        return Err(Error::invalid_params("offset out of range"));
+    }
+    let unit = Arc::new(
+        Unit::new(&config.port_svc, config.unit, sensor)
+            .write_roles(config.write_roles)
+            .identity(config.identity)
+            .access(config.access),
+    );
     let rpc = initial
         .init_rpc(Handlers {
             info,
-            unit: config.unit,
-            tp: config.tp,
-            reg: config.reg,
+            unit: unit.clone(),
         })
         .await?;
     initial.drop_privileges()?;
     let client = rpc.client().clone();
-    RPC.set(rpc.clone())
-        .map_err(|_| Error::core("Unable to set RPC"))?;
-    eva_sim_modbus::init(&config.port_svc, &mut *client.lock().await).await?;
     svc_init_logs(&initial, client.clone())?;
     svc_start_signal_handlers();
+    unit.start(rpc.clone(), timeout).await?;
     svc_mark_ready(&client).await?;
     info!("{} started ({})", DESCRIPTION, initial.id());
     svc_block(&rpc).await;
     svc_mark_terminating(&client).await?;
+    unit.stop(timeout).await;
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Reg, Sensor};
+    use eva_sim_modbus::data::{DataType, Encoding};
+    use eva_sim_modbus::Device;
+
+    #[test]
+    fn window() {
+        let sensor = Sensor {
+            encoding: Encoding::new(DataType::Real),
+            reg: Reg::Input,
+            offset: 100,
+        };
+        assert_eq!(sensor.read_exception(4, 100, 2), None);
+        assert_eq!(sensor.read_exception(4, 0, 2), Some(0x02));
+        assert_eq!(sensor.read_exception(4, 101, 2), Some(0x02));
+        assert_eq!(sensor.read_exception(3, 100, 2), Some(0x02));
+    }
+}